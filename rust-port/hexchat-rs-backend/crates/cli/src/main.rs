@@ -1,19 +1,37 @@
 
 use anyhow::Result;
-use tracing::info;
+use tracing::{debug, info};
+use std::collections::HashMap;
 use std::env;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::task::JoinHandle;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// HH:MM:SS (UTC) of an event, so replayed lines show when they were said.
+fn stamp(t: SystemTime) -> String {
+    let secs = t.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    format!("{:02}:{:02}:{:02}", secs / 3600 % 24, secs / 60 % 60, secs % 60)
+}
 
 #[tokio::main(flavor = "multi_thread")]
 async fn main() -> Result<()> {
     tracing_subscriber::fmt().with_env_filter("info").init();
 
-    let mut args = env::args().skip(1);
+    // a settings file gives the starting values; flags override them
+    let argv: Vec<String> = env::args().skip(1).collect();
+    let settings = match argv.iter().position(|a| a == "--config").and_then(|i| argv.get(i + 1)) {
+        Some(path) => Some(config::Settings::load(&path.into())?),
+        None => None,
+    };
+    let mut args = argv.into_iter();
     let mut server = "irc.libera.chat".to_string();
     let mut port: u16 = 6697;
     let mut tls = true;
     let mut cert: Option<String> = None;
     let mut key: Option<String> = None;
     let mut nick = "HexRs".to_string();
+    let mut alt_nicks: Vec<String> = Vec::new();
+    let mut regain: Option<net::cap_sasl::Regain> = None;
     let mut user = "hexrs".to_string();
     let mut realname = "HexChat RS".to_string();
     let mut join: Option<String> = None;
@@ -22,14 +40,48 @@ async fn main() -> Result<()> {
     let mut sasl_scram512: Option<(String, String)> = None;
     let mut sasl_external: bool = false;
     let mut sasl_authzid: Option<String> = None;
+    let mut throttle = true;
+    let mut ping_interval = Duration::from_secs(30);
+    let mut ping_timeout = Duration::from_secs(60);
+    let mut reconnect_delay = Duration::from_secs(10);
+    let mut max_lines = core::ScrollbackConfig::default().max_lines;
+    let mut chan_scrollback: Vec<(String, usize)> = Vec::new();
+    if let Some(s) = &settings {
+        server = s.server.clone();
+        port = s.port;
+        tls = s.use_tls;
+        nick = s.nick.clone();
+        alt_nicks = s.alternate_nicks.clone();
+        user = s.user.clone();
+        realname = s.realname.clone();
+        if !s.autojoin.is_empty() { join = Some(s.autojoin.join(",")); }
+        throttle = s.net_throttle;
+        max_lines = s.text_max_lines;
+        chan_scrollback = s.chanopts.iter().filter_map(|(c, o)| Some((c.clone(), o.text_scrollback?))).collect();
+    }
 
     while let Some(a) = args.next() {
         match a.as_str() {
+            "--config" => { args.next(); }
             "--server" => server = args.next().unwrap_or(server),
             "--port" => port = args.next().and_then(|s| s.parse().ok()).unwrap_or(port),
             "--tls" => tls = true,
             "--notls" => tls = false,
+            "--no-throttle" => throttle = false,
+            "--ping-interval" => ping_interval = args.next().and_then(|s| s.parse().ok()).map(Duration::from_secs).unwrap_or(ping_interval),
+            "--ping-timeout" => ping_timeout = args.next().and_then(|s| s.parse().ok()).map(Duration::from_secs).unwrap_or(ping_timeout),
+            "--reconnect-delay" => reconnect_delay = args.next().and_then(|s| s.parse().ok()).map(Duration::from_secs).unwrap_or(reconnect_delay),
             "--nick" => nick = args.next().unwrap_or(nick),
+            "--alt-nick" => { if let Some(n) = args.next() { alt_nicks.push(n); } }
+            "--regain" => {
+                // monitor | ghost:<password> | regain:<password>
+                regain = match args.next().as_deref().map(|s| s.split_once(':').unwrap_or((s, ""))) {
+                    Some(("monitor", _)) => Some(net::cap_sasl::Regain::Monitor),
+                    Some(("ghost", p)) => Some(net::cap_sasl::Regain::Ghost{ password: p.to_string() }),
+                    Some(("regain", p)) => Some(net::cap_sasl::Regain::Regain{ password: p.to_string() }),
+                    _ => None,
+                };
+            }
             "--user" => user = args.next().unwrap_or(user),
             "--realname" => realname = args.next().unwrap_or(realname),
             "--cert" => cert = args.next(),
@@ -54,24 +106,8 @@ async fn main() -> Result<()> {
         }
     }
 
-    info!("connecting to {}:{} (tls={}) as {}", server, port, tls, nick);
-
-    
-let tls_cfg = if tls {
-    if let (Some(c), Some(k)) = (cert.clone(), key.clone()) {
-        net::TlsConfig::Rustls { client_auth: Some(net::ClientAuth{ cert_path: c, key_path: k }) }
-    } else {
-        net::TlsConfig::Rustls { client_auth: None }
-    }
-} else { net::TlsConfig::Off };
-let mut conn = net::Connection::connect(&server, port, tls_cfg).await?;
-
-
-    // CAP/SASL negotiation
-    
-let include_sasl = sasl_plain.is_some() || sasl_scram256.is_some() || sasl_scram512.is_some() || sasl_external;
-let mut caps = net::cap_sasl::CapRequest::defaults(include_sasl);
-
+    let include_sasl = sasl_plain.is_some() || sasl_scram256.is_some() || sasl_scram512.is_some() || sasl_external;
+    let nicks = net::cap_sasl::NickPrefs { primary: nick.clone(), alternates: alt_nicks, regain };
     let sasl = if sasl_external {
         Some(net::cap_sasl::SaslMech::External { authzid: sasl_authzid.clone() })
     } else if let Some((u,p)) = sasl_scram512.clone() {
@@ -84,32 +120,211 @@ let mut caps = net::cap_sasl::CapRequest::defaults(include_sasl);
         username: u,
         password: p,
     })
-    ;
-    net::cap_sasl::negotiate(&mut conn, &nick, &user, &realname, caps, sasl).await?;
+    } else { None };
+    let opts = Opts {
+        server, port, tls, cert, key, nicks, user, realname, join, include_sasl, sasl, throttle,
+        ping: core::PingConfig { interval: ping_interval, timeout: ping_timeout },
+        reconnect_delay, max_lines, chan_scrollback,
+    };
 
-    // If requested, join a channel now that we're welcomed
-    if let Some(ch) = &join {
-        conn.send_raw(&format!("JOIN {}", ch)).await?;
+    // every network behind a soju-style bouncer gets a session bound to it
+    let (found_tx, mut found_rx) = tokio::sync::mpsc::unbounded_channel::<NetworkUpdate>();
+    let primary = tokio::spawn(session(opts.clone(), None, Some(found_tx)));
+    let mut bound: HashMap<String, JoinHandle<Result<()>>> = HashMap::new();
+    let open = |id: String| {
+        info!("bouncer network {}: opening a bound connection", id);
+        // --join is meant for the bouncer connection, not every upstream network
+        let opts = Opts { join: None, ..opts.clone() };
+        tokio::spawn(session(opts, Some(id), None))
+    };
+    while let Some(update) = found_rx.recv().await {
+        match update {
+            NetworkUpdate::Added(id) if !bound.contains_key(&id) => {
+                bound.insert(id.clone(), open(id));
+            }
+            NetworkUpdate::Removed(id) => {
+                if let Some(h) = bound.remove(&id) {
+                    info!("bouncer network {} removed: closing its connection", id);
+                    h.abort();
+                }
+            }
+            NetworkUpdate::Added(_) => {}
+            // after a reconnect: networks deleted meanwhile never sent a removal
+            NetworkUpdate::Listed(ids) => {
+                bound.retain(|id, h| {
+                    let keep = ids.contains(id);
+                    if !keep {
+                        info!("bouncer network {} is gone: closing its connection", id);
+                        h.abort();
+                    }
+                    keep
+                });
+                for id in ids {
+                    if !bound.contains_key(&id) { bound.insert(id.clone(), open(id)); }
+                }
+            }
+        }
     }
+    primary.await?
+}
+
+/// Bouncer networks appearing and disappearing, as seen by the unbound session.
+enum NetworkUpdate {
+    Added(String),
+    Removed(String),
+    /// A full LISTNETWORKS answer: every network that still exists.
+    Listed(Vec<String>),
+}
+
+#[derive(Clone)]
+struct Opts {
+    server: String,
+    port: u16,
+    tls: bool,
+    cert: Option<String>,
+    key: Option<String>,
+    nicks: net::cap_sasl::NickPrefs,
+    user: String,
+    realname: String,
+    join: Option<String>,
+    include_sasl: bool,
+    sasl: Option<net::cap_sasl::SaslMech>,
+    throttle: bool,
+    ping: core::PingConfig,
+    reconnect_delay: Duration,
+    /// text_max_lines
+    max_lines: usize,
+    /// chanopt text_scrollback, per channel
+    chan_scrollback: Vec<(String, usize)>,
+}
 
-    let engine = core::Engine::new(&server, &nick);
+/// One connection, reconnecting forever. `bind` ties it to a bouncer network;
+/// the unbound session reports the networks it discovers through `found`.
+async fn session(opts: Opts, bind: Option<String>, found: Option<UnboundedSender<NetworkUpdate>>) -> Result<()> {
+    let Opts { server, port, tls, cert, key, nicks, user, realname, join, include_sasl, sasl, throttle, ping, reconnect_delay, max_lines, chan_scrollback } = opts;
+    let network = match &bind { Some(id) => format!("{}/{}", server, id), None => server.clone() };
+    let engine = core::Engine::new(&network, &nicks.primary);
+    engine.set_ping_config(ping);
+    {
+        let mut sb = engine.scrollback();
+        let cfg = core::ScrollbackConfig { max_lines, ..sb.config().clone() };
+        sb.set_config(cfg);
+        for (chan, lines) in chan_scrollback {
+            sb.set_limit(core::BufferId::Channel(chan), Some(lines));
+        }
+    }
 
     loop {
-        let msg = match conn.next_message().await {
-            Ok(m) => m,
-            Err(e) => { eprintln!("recv error: {e}"); break; }
+        info!("connecting to {}:{} (tls={}) as {}", server, port, tls, nicks.primary);
+
+        let tls_cfg = if tls {
+            if let (Some(c), Some(k)) = (cert.clone(), key.clone()) {
+                net::TlsConfig::Rustls { client_auth: Some(net::ClientAuth{ cert_path: c, key_path: k }) }
+            } else {
+                net::TlsConfig::Rustls { client_auth: None }
+            }
+        } else { net::TlsConfig::Off };
+        let mut conn = match net::Connection::connect(&server, port, tls_cfg).await {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!("connect error: {e}");
+                tokio::time::sleep(reconnect_delay).await;
+                continue;
+            }
         };
-        let ev = engine.on_message(msg.clone());
-        match &ev {
-            core::Event::PrivMsg{ from, target, text } => {
-                info!("{} -> {}: {}", from, target, text);
+        if !throttle { conn.send_queue_mut().set_throttle(net::sendq::Throttle::off()); }
+
+        // CAP/SASL negotiation
+        let mut caps = net::cap_sasl::CapRequest::defaults(include_sasl);
+        caps.bind_network = bind.clone();
+        // the engine only watches for a dead link once we are registered
+        let registering = net::cap_sasl::negotiate(&mut conn, &nicks, &user, &realname, caps, sasl.clone());
+        let registered = match tokio::time::timeout(ping.timeout, registering).await {
+            Ok(Ok(r)) => r,
+            Ok(Err(e)) => {
+                eprintln!("registration error: {e}");
+                tokio::time::sleep(reconnect_delay).await;
+                continue;
             }
-            core::Event::Join{ nick, channel } => {
-                info!("{} joined {}", nick, channel);
+            Err(_) => {
+                eprintln!("registration timed out, reconnecting");
+                tokio::time::sleep(reconnect_delay).await;
+                continue;
+            }
+        };
+        engine.connected(&registered.nick);
+        engine.set_caps(registered.caps);
+        if bind.is_none() && engine.state().has_cap("soju.im/bouncer-networks") { engine.list_networks(); }
+
+        // If requested, join a channel now that we're welcomed
+        if let Some(ch) = &join {
+            if let Err(e) = conn.send(&format!("JOIN {}", ch)).await {
+                eprintln!("send error: {e}");
+                tokio::time::sleep(reconnect_delay).await;
+                continue;
+            }
+        }
+
+        let mut ticker = tokio::time::interval(Duration::from_secs(1));
+        'conn: loop {
+            let wait = conn.next_send_in();
+            let events = tokio::select! {
+                m = conn.next_message() => match m {
+                    Ok(m) => engine.on_message(m).into_iter().collect(),
+                    Err(e) => { eprintln!("recv error: {e}"); break; }
+                },
+                _ = tokio::time::sleep(wait.unwrap_or_default()), if wait.is_some() => {
+                    if let Err(e) = conn.flush_queue().await { eprintln!("send error: {e}"); break; }
+                    continue;
+                }
+                _ = ticker.tick() => engine.tick(),
+            };
+            for line in engine.take_outgoing() {
+                if let Err(e) = conn.send(&line).await { eprintln!("send error: {e}"); break 'conn; }
+            }
+            for ev in &events {
+                match &ev.event {
+                    core::Event::PrivMsg{ from, target, text, .. } => {
+                        info!("[{}] {} -> {}: {}", stamp(ev.time), from, target, text);
+                    }
+                    core::Event::Join{ nick, channel } => {
+                        info!("[{}] {} joined {}", stamp(ev.time), nick, channel);
+                    }
+                    core::Event::Netsplit{ servers, nicks, .. } => {
+                        info!("netsplit {} <-> {}: {} users", servers.0, servers.1, nicks.len());
+                    }
+                    core::Event::MonitorOffline(gone) => {
+                        // primary nick became free: grab it
+                        if gone.iter().any(|n| n == &nicks.primary) && engine.state().nick != nicks.primary {
+                            if let Err(e) = conn.send(&format!("NICK {}", nicks.primary)).await { eprintln!("send error: {e}"); break 'conn; }
+                        }
+                    }
+                    core::Event::Nick{ new, .. } => {
+                        if new == &nicks.primary && matches!(nicks.regain, Some(net::cap_sasl::Regain::Monitor | net::cap_sasl::Regain::Ghost{ .. })) {
+                            if let Err(e) = conn.send(&format!("MONITOR - {}", nicks.primary)).await { eprintln!("send error: {e}"); break 'conn; }
+                        }
+                    }
+                    core::Event::Batch{ kind, .. } if kind == "soju.im/bouncer-networks" => {
+                        // the answer to LISTNETWORKS; the engine's list was reset on connect
+                        let ids = engine.bouncer_networks().into_iter().map(|n| n.id).collect();
+                        if let Some(tx) = &found { let _ = tx.send(NetworkUpdate::Listed(ids)); }
+                    }
+                    core::Event::BouncerNetwork{ id, network } => {
+                        let update = if network.is_some() { NetworkUpdate::Added(id.clone()) } else { NetworkUpdate::Removed(id.clone()) };
+                        if let Some(tx) = &found { let _ = tx.send(update); }
+                    }
+                    core::Event::LagUpdate{ lag_ms } => {
+                        debug!("lag {} ms", lag_ms);
+                    }
+                    core::Event::PingTimeout => {
+                        eprintln!("ping timeout, reconnecting");
+                        break 'conn;
+                    }
+                    _ => {}
+                }
             }
-            _ => {}
         }
-    }
 
-    Ok(())
+        tokio::time::sleep(reconnect_delay).await;
+    }
 }
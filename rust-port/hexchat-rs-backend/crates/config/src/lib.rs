@@ -1,19 +1,38 @@
 use anyhow::Result;
 use camino::Utf8PathBuf;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs;
 
+/// Per-channel overrides, after HexChat's chanopt.c.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ChanOpts {
+    /// Scrollback lines kept for this channel; unset falls back to `text_max_lines`.
+    pub text_scrollback: Option<usize>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Settings {
     pub server: String,
     pub port: u16,
     pub use_tls: bool,
     pub nick: String,
+    #[serde(default)]
+    pub alternate_nicks: Vec<String>,
     pub user: String,
     pub realname: String,
     pub autojoin: Vec<String>,
+    #[serde(default = "default_true")]
+    pub net_throttle: bool,
+    #[serde(default = "default_max_lines")]
+    pub text_max_lines: usize,
+    #[serde(default)]
+    pub chanopts: HashMap<String, ChanOpts>,
 }
 
+fn default_true() -> bool { true }
+fn default_max_lines() -> usize { 5000 }
+
 impl Default for Settings {
     fn default() -> Self {
         Self {
@@ -21,9 +40,13 @@ impl Default for Settings {
             port: 6697,
             use_tls: true,
             nick: "HexRs".into(),
+            alternate_nicks: vec!["HexRs_".into(), "HexRs__".into()],
             user: "hexrs".into(),
             realname: "HexChat RS".into(),
             autojoin: vec!["#rust".into()],
+            net_throttle: true,
+            text_max_lines: default_max_lines(),
+            chanopts: HashMap::new(),
         }
     }
 }
@@ -0,0 +1,123 @@
+// Unread/highlight counters and tab activity levels per buffer, following
+// HexChat's tab colouring (new data / new message / new highlight) and the
+// is_hilight() check in inbound.c.
+use crate::scrollback::BufferId;
+use crate::highlight::HighlightConfig;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+/// Ordered so that a buffer's level only ever rises until it is marked read.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Activity {
+    #[default]
+    None,
+    /// joins, parts, nick changes and other non-message events
+    Events,
+    Message,
+    Highlight,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BufferActivity {
+    pub unread: u32,
+    pub highlights: u32,
+    pub level: Activity,
+}
+
+#[derive(Debug, Default)]
+pub struct ActivityTracker {
+    cfg: HighlightConfig,
+    buffers: HashMap<BufferId, BufferActivity>,
+    focused: Option<BufferId>,
+    /// Read position per buffer (draft/read-marker): lines up to it are already seen.
+    markers: HashMap<BufferId, SystemTime>,
+    /// What was counted since each buffer was last read, so a read marker can recount it.
+    noted: HashMap<BufferId, Vec<(SystemTime, Activity)>>,
+}
+
+impl ActivityTracker {
+    pub fn new(cfg: HighlightConfig) -> Self { Self { cfg, ..Self::default() } }
+
+    pub fn config(&self) -> &HighlightConfig { &self.cfg }
+    pub fn set_config(&mut self, cfg: HighlightConfig) { self.cfg = cfg; }
+
+    /// The buffer the user is looking at; activity there is not counted (HexChat's current_tab).
+    pub fn set_focused(&mut self, id: Option<BufferId>) {
+        if let Some(id) = &id { self.mark_read(id); }
+        self.focused = id;
+    }
+    pub fn focused(&self) -> Option<&BufferId> { self.focused.as_ref() }
+
+    pub fn note(&mut self, id: &BufferId, level: Activity, time: SystemTime) {
+        if self.focused.as_ref() == Some(id) || level == Activity::None { return; }
+        self.noted.entry(id.clone()).or_default().push((time, level));
+        let a = self.buffers.entry(id.clone()).or_default();
+        if level >= Activity::Message { a.unread += 1; }
+        if level == Activity::Highlight { a.highlights += 1; }
+        a.level = a.level.max(level);
+    }
+
+    /// Counts again only what arrived after `read_up_to`, e.g. when another client read part of the buffer.
+    pub fn recount(&mut self, id: &BufferId, read_up_to: SystemTime) {
+        let Some(noted) = self.noted.get_mut(id) else { return };
+        noted.retain(|(t, _)| *t > read_up_to);
+        let mut a = BufferActivity::default();
+        for &(_, level) in noted.iter() {
+            if level >= Activity::Message { a.unread += 1; }
+            if level == Activity::Highlight { a.highlights += 1; }
+            a.level = a.level.max(level);
+        }
+        if noted.is_empty() { self.mark_read(id); } else { self.buffers.insert(id.clone(), a); }
+    }
+
+    pub fn get(&self, id: &BufferId) -> BufferActivity { self.buffers.get(id).cloned().unwrap_or_default() }
+    pub fn iter(&self) -> impl Iterator<Item = (&BufferId, &BufferActivity)> { self.buffers.iter() }
+
+    pub fn read_marker(&self, id: &BufferId) -> Option<SystemTime> { self.markers.get(id).copied() }
+
+    /// Moves the read marker forward; returns false when `time` is not past it.
+    pub fn set_read_marker(&mut self, id: &BufferId, time: SystemTime) -> bool {
+        if self.read_marker(id).is_some_and(|m| m >= time) { return false; }
+        self.markers.insert(id.clone(), time);
+        true
+    }
+
+    pub fn rename(&mut self, from: &BufferId, to: BufferId) {
+        if let Some(m) = self.markers.remove(from) { self.markers.insert(to.clone(), m); }
+        if let Some(a) = self.buffers.remove(from) { self.buffers.insert(to.clone(), a); }
+        if let Some(n) = self.noted.remove(from) { self.noted.insert(to.clone(), n); }
+        if self.focused.as_ref() == Some(from) { self.focused = Some(to); }
+    }
+
+    pub fn mark_read(&mut self, id: &BufferId) {
+        self.buffers.remove(id);
+        self.noted.remove(id);
+    }
+
+    pub fn mark_all_read(&mut self) {
+        self.buffers.clear();
+        self.noted.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn recount_keeps_what_is_past_the_marker() {
+        let at = |s| UNIX_EPOCH + Duration::from_secs(s);
+        let id = BufferId::Channel("#c".into());
+        let mut act = ActivityTracker::default();
+        act.note(&id, Activity::Highlight, at(1));
+        act.note(&id, Activity::Message, at(2));
+        act.note(&id, Activity::Message, at(3));
+        act.recount(&id, at(2));
+        let a = act.get(&id);
+        assert_eq!((a.unread, a.highlights, a.level), (1, 0, Activity::Message));
+        act.recount(&id, at(3));
+        assert_eq!(act.get(&id).unread, 0);
+    }
+}
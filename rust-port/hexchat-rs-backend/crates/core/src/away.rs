@@ -0,0 +1,66 @@
+// Our own away state, auto-away driven by idle time the frontend reports,
+// and HexChat's away_show_once de-duplication of RPL_AWAY.
+use std::collections::HashMap;
+use std::time::Duration;
+
+#[derive(Debug, Clone)]
+pub struct AwayConfig {
+    /// Go away after this much idle time; `None` disables auto-away.
+    pub auto_after: Option<Duration>,
+    /// away_reason
+    pub reason: String,
+    /// Show RPL_AWAY only once per nick until the message changes (away_show_once).
+    pub show_once: bool,
+}
+
+impl Default for AwayConfig {
+    fn default() -> Self { Self { auto_after: None, reason: "I'm busy".into(), show_once: true } }
+}
+
+#[derive(Debug, Default)]
+pub struct AwayTracker {
+    pub cfg: AwayConfig,
+    /// we set away ourselves because of idling, so activity may bring us back
+    auto: bool,
+    /// message of the last AWAY we sent, reported once the server confirms with 306
+    pending: Option<String>,
+    /// last RPL_AWAY text seen per nick
+    seen: HashMap<String, String>,
+}
+
+pub(crate) enum IdleAction {
+    GoAway(String),
+    Return,
+}
+
+impl AwayTracker {
+    pub fn is_auto(&self) -> bool { self.auto }
+
+    pub(crate) fn sent_away(&mut self, message: Option<&str>, auto: bool) {
+        self.pending = message.map(str::to_string);
+        self.auto = auto && message.is_some();
+    }
+
+    pub(crate) fn confirmed(&mut self) -> Option<String> { self.pending.clone() }
+
+    pub(crate) fn idle(&mut self, idle: Duration, currently_away: bool) -> Option<IdleAction> {
+        let after = self.cfg.auto_after?;
+        // an auto-away still waiting for its 306 counts as away already
+        let currently_away = currently_away || (self.auto && self.pending.is_some());
+        if idle >= after && !currently_away {
+            return Some(IdleAction::GoAway(self.cfg.reason.clone()));
+        }
+        if idle < after && currently_away && self.auto {
+            return Some(IdleAction::Return);
+        }
+        None
+    }
+
+    /// Whether an RPL_AWAY for `nick` is new enough to show.
+    pub(crate) fn should_show(&mut self, nick: &str, message: &str) -> bool {
+        if !self.cfg.show_once { return true; }
+        self.seen.insert(nick.to_ascii_lowercase(), message.to_string()).as_deref() != Some(message)
+    }
+
+    pub(crate) fn forget(&mut self, nick: &str) { self.seen.remove(&nick.to_ascii_lowercase()); }
+}
@@ -0,0 +1,81 @@
+// IRCv3 BATCH: lines tagged with an open batch's reference are held back
+// until the batch closes, then handed over together, nested batches included.
+use proto::Message;
+use std::collections::{BTreeMap, HashMap};
+
+#[derive(Debug, Clone)]
+pub enum BatchItem {
+    Message(Message),
+    Batch(Batch),
+}
+
+#[derive(Debug, Clone)]
+pub struct Batch {
+    pub reference: String,
+    /// netsplit, netjoin, chathistory, labeled-response, draft/multiline...
+    pub kind: String,
+    pub params: Vec<String>,
+    /// Tags of the opening BATCH line (label, msgid, time...).
+    pub tags: BTreeMap<String, String>,
+    /// Contents in the order received.
+    pub items: Vec<BatchItem>,
+}
+
+impl Batch {
+    pub fn tag(&self, key: &str) -> Option<&str> { self.tags.get(key).map(String::as_str) }
+}
+
+pub enum Handled {
+    /// Not part of any batch; process it as usual.
+    Live(Message),
+    Held,
+    /// A top-level batch closed.
+    Closed(Batch),
+}
+
+#[derive(Debug)]
+struct Open {
+    parent: Option<String>,
+    batch: Batch,
+}
+
+#[derive(Debug, Default)]
+pub struct BatchTracker {
+    open: HashMap<String, Open>,
+}
+
+impl BatchTracker {
+    pub fn handle(&mut self, msg: Message) -> Handled {
+        let parent = msg.tag("batch").filter(|r| self.open.contains_key(*r)).map(str::to_string);
+        if msg.command == "BATCH" {
+            let r = msg.params.first().map(String::as_str).unwrap_or("");
+            if let Some(reference) = r.strip_prefix('+') {
+                let batch = Batch {
+                    reference: reference.to_string(),
+                    kind: msg.params.get(1).cloned().unwrap_or_default(),
+                    params: msg.params.get(2..).unwrap_or_default().to_vec(),
+                    tags: msg.tags.clone(),
+                    items: Vec::new(),
+                };
+                self.open.insert(reference.to_string(), Open { parent, batch });
+                return Handled::Held;
+            }
+            if let Some(reference) = r.strip_prefix('-') {
+                let Some(done) = self.open.remove(reference) else { return Handled::Held };
+                return match done.parent.and_then(|p| self.open.get_mut(&p)) {
+                    Some(up) => { up.batch.items.push(BatchItem::Batch(done.batch)); Handled::Held }
+                    None => Handled::Closed(done.batch),
+                };
+            }
+        }
+        match parent.and_then(|p| self.open.get_mut(&p)) {
+            Some(o) => { o.batch.items.push(BatchItem::Message(msg)); Handled::Held }
+            None => Handled::Live(msg),
+        }
+    }
+
+    pub fn in_progress(&self) -> bool { !self.open.is_empty() }
+
+    /// Drops half-received batches, e.g. after a reconnect.
+    pub fn clear(&mut self) { self.open.clear(); }
+}
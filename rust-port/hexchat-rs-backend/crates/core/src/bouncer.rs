@@ -0,0 +1,49 @@
+// soju.im/bouncer-networks: the upstream networks behind a bouncer, kept
+// current from BOUNCER NETWORK replies and notifications.
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BouncerNetwork {
+    /// netid, as used by BOUNCER BIND
+    pub id: String,
+    /// name, host, port, nickname, state...
+    pub attrs: BTreeMap<String, String>,
+}
+
+impl BouncerNetwork {
+    pub fn name(&self) -> Option<&str> { self.attrs.get("name").map(String::as_str) }
+    /// connected, connecting or disconnected
+    pub fn state(&self) -> Option<&str> { self.attrs.get("state").map(String::as_str) }
+}
+
+/// Attributes in the tag-like syntax BOUNCER commands take.
+pub fn format_attrs(attrs: &BTreeMap<String, String>) -> String {
+    attrs.iter().map(|(k, v)| format!("{}={}", k, proto::escape_tag(v))).collect::<Vec<_>>().join(";")
+}
+
+#[derive(Debug, Default)]
+pub struct BouncerNetworks {
+    networks: BTreeMap<String, BouncerNetwork>,
+}
+
+impl BouncerNetworks {
+    pub fn get(&self, id: &str) -> Option<&BouncerNetwork> { self.networks.get(id) }
+    pub fn iter(&self) -> impl Iterator<Item = &BouncerNetwork> { self.networks.values() }
+
+    /// Applies `BOUNCER NETWORK <id> <attrs>`; `*` deletes the network and
+    /// valueless attributes are unset. Returns the network as it now stands.
+    pub(crate) fn update(&mut self, id: &str, attrs: &str) -> Option<BouncerNetwork> {
+        if attrs == "*" {
+            self.networks.remove(id);
+            return None;
+        }
+        let net = self.networks.entry(id.to_string()).or_insert_with(|| BouncerNetwork { id: id.to_string(), ..Default::default() });
+        for (k, v) in proto::parse_tags(attrs) {
+            if v.is_empty() { net.attrs.remove(&k); } else { net.attrs.insert(k, v); }
+        }
+        Some(net.clone())
+    }
+
+    pub(crate) fn clear(&mut self) { self.networks.clear(); }
+}
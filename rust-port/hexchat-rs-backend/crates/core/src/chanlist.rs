@@ -0,0 +1,105 @@
+// Channel directory built from LIST replies (321/322/323), the data behind
+// HexChat's channel list window. Filters go to the server as ELIST
+// conditions where advertised and are always re-applied locally.
+use crate::{strip_formatting, wildmatch};
+use serde::{Deserialize, Serialize};
+use std::time::SystemTime;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListEntry {
+    pub channel: String,
+    pub users: u32,
+    pub topic: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ListFilter {
+    /// Wildcard pattern for the channel name.
+    pub name: Option<String>,
+    /// Case-insensitive substring of the topic.
+    pub topic: Option<String>,
+    pub min_users: Option<u32>,
+    pub max_users: Option<u32>,
+}
+
+impl ListFilter {
+    pub fn matches(&self, e: &ListEntry) -> bool {
+        self.name.as_ref().is_none_or(|m| wildmatch(m, &e.channel))
+            && self.topic.as_ref().is_none_or(|t| strip_formatting(&e.topic).to_lowercase().contains(&t.to_lowercase()))
+            && self.min_users.is_none_or(|n| e.users >= n)
+            && self.max_users.is_none_or(|n| e.users <= n)
+    }
+
+    /// The LIST command for this filter, using the ELIST conditions the server advertises.
+    pub fn command(&self, elist: &str) -> String {
+        let elist = elist.to_ascii_uppercase();
+        let mut conds: Vec<String> = Vec::new();
+        if elist.contains('U') {
+            if let Some(n) = self.min_users { conds.push(format!(">{}", n.saturating_sub(1))); }
+            if let Some(n) = self.max_users { conds.push(format!("<{}", n.saturating_add(1))); }
+        }
+        if elist.contains('M') {
+            if let Some(m) = &self.name { conds.push(m.clone()); }
+        }
+        if conds.is_empty() { "LIST".to_string() } else { format!("LIST {}", conds.join(",")) }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ChannelList {
+    entries: Vec<ListEntry>,
+    /// 322 replies seen, matching or not.
+    received: usize,
+    filter: ListFilter,
+    in_progress: bool,
+    cancelled: bool,
+    fetched_at: Option<SystemTime>,
+}
+
+impl ChannelList {
+    pub fn start(&mut self, filter: ListFilter) {
+        self.entries.clear();
+        self.received = 0;
+        self.filter = filter;
+        self.in_progress = true;
+        self.cancelled = false;
+    }
+
+    /// Stops collecting; replies still in flight are dropped until the server's 323.
+    pub fn cancel(&mut self) {
+        if self.in_progress { self.cancelled = true; }
+    }
+
+    pub fn in_progress(&self) -> bool { self.in_progress && !self.cancelled }
+    /// Entries kept so far, i.e. those that passed the filter.
+    pub fn count(&self) -> usize { self.entries.len() }
+    /// Replies received so far, for progress while a narrow filter keeps `count` low.
+    pub fn received(&self) -> usize { self.received }
+    /// When the last complete listing finished.
+    pub fn fetched_at(&self) -> Option<SystemTime> { self.fetched_at }
+    pub fn entries(&self) -> &[ListEntry] { &self.entries }
+
+    /// Cached entries matching `filter`, biggest channels first.
+    pub fn search(&self, filter: &ListFilter) -> Vec<&ListEntry> {
+        let mut out: Vec<&ListEntry> = self.entries.iter().filter(|e| filter.matches(e)).collect();
+        out.sort_by(|a, b| b.users.cmp(&a.users).then_with(|| a.channel.cmp(&b.channel)));
+        out
+    }
+
+    /// Adds a 322 reply; returns it when it passes the active filter.
+    pub(crate) fn add(&mut self, e: ListEntry) -> Option<ListEntry> {
+        // a LIST we did not start ourselves is collected too
+        if !self.in_progress { self.start(ListFilter::default()); }
+        if self.cancelled { return None; }
+        self.received += 1;
+        if !self.filter.matches(&e) { return None; }
+        self.entries.push(e.clone());
+        Some(e)
+    }
+
+    pub(crate) fn finish(&mut self) {
+        if !self.cancelled { self.fetched_at = Some(SystemTime::now()); }
+        self.in_progress = false;
+        self.cancelled = false;
+    }
+}
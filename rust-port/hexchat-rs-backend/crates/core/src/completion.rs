@@ -0,0 +1,154 @@
+// Tab completion of nicks, channels and commands, after HexChat's
+// tab_comp(): nicks ordered by who spoke last (completion_sort), and
+// pressing tab again cycles through the candidates.
+use crate::scrollback::BufferId;
+use crate::ServerState;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CompletionSort {
+    Alphabetical,
+    /// Whoever spoke most recently first.
+    #[default]
+    LastSpoke,
+}
+
+#[derive(Debug, Clone)]
+pub struct CompletionConfig {
+    /// Appended to a nick completed at the start of the line (completion_suffix).
+    pub suffix: String,
+    pub sort: CompletionSort,
+    /// Slash-command names and aliases, without the slash.
+    pub commands: Vec<String>,
+}
+
+impl Default for CompletionConfig {
+    fn default() -> Self { Self { suffix: ": ".into(), sort: CompletionSort::LastSpoke, commands: Vec::new() } }
+}
+
+/// The input line after completing, with the cursor just past the inserted text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Completion {
+    pub line: String,
+    pub cursor: usize,
+    /// Everything that matched, in cycling order.
+    pub candidates: Vec<String>,
+}
+
+#[derive(Debug)]
+struct Cycle {
+    buffer: BufferId,
+    /// line and cursor we handed out last, to recognise a repeated tab
+    line: String,
+    cursor: usize,
+    before: String,
+    after: String,
+    suffix: String,
+    candidates: Vec<String>,
+    index: usize,
+}
+
+impl Cycle {
+    fn result(&mut self) -> Completion {
+        let cand = &self.candidates[self.index];
+        let suffix = if self.after.starts_with(char::is_whitespace) { self.suffix.trim_end() } else { &self.suffix };
+        self.line = format!("{}{}{}{}", self.before, cand, suffix, self.after);
+        self.cursor = self.before.len() + cand.len() + suffix.len();
+        Completion { line: self.line.clone(), cursor: self.cursor, candidates: self.candidates.clone() }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Completer {
+    pub cfg: CompletionConfig,
+    cycle: Option<Cycle>,
+}
+
+impl Completer {
+    pub fn new(cfg: CompletionConfig) -> Self { Self { cfg, cycle: None } }
+
+    /// Completes the word before `cursor` (a byte offset) in `line`, or moves
+    /// on to the next candidate when called again on the line it produced.
+    pub fn complete(&mut self, st: &ServerState, buffer: &BufferId, line: &str, cursor: usize) -> Option<Completion> {
+        if let Some(c) = self.cycle.as_mut().filter(|c| &c.buffer == buffer && c.line == line && c.cursor == cursor) {
+            c.index = (c.index + 1) % c.candidates.len();
+            return Some(c.result());
+        }
+        self.cycle = None;
+        if cursor > line.len() || !line.is_char_boundary(cursor) { return None; }
+        let start = line[..cursor].char_indices().rev().find(|(_, c)| c.is_whitespace()).map_or(0, |(i, c)| i + c.len_utf8());
+        let word = &line[start..cursor];
+        if word.is_empty() { return None; }
+
+        let (candidates, suffix) = if let Some(cmd) = word.strip_prefix('/').filter(|_| start == 0) {
+            let mut v: Vec<String> = self.cfg.commands.iter().filter(|c| starts_with(c, cmd)).map(|c| format!("/{}", c)).collect();
+            v.sort_by_key(|c| c.to_lowercase());
+            (v, " ".to_string())
+        } else if st.is_channel(word) {
+            let mut v: Vec<String> = st.channels.values().map(|c| c.name.clone()).filter(|c| starts_with(c, word)).collect();
+            v.sort_by_key(|c| c.to_lowercase());
+            (v, " ".to_string())
+        } else {
+            let suffix = if start == 0 { self.cfg.suffix.clone() } else { " ".to_string() };
+            (self.nicks(st, buffer, word), suffix)
+        };
+        if candidates.is_empty() { return None; }
+        let mut cycle = Cycle {
+            buffer: buffer.clone(),
+            line: String::new(),
+            cursor: 0,
+            before: line[..start].to_string(),
+            after: line[cursor..].to_string(),
+            suffix,
+            candidates,
+            index: 0,
+        };
+        let out = cycle.result();
+        self.cycle = Some(cycle);
+        Some(out)
+    }
+
+    /// Forgets the cycle in progress, e.g. once the user types something else.
+    pub fn reset(&mut self) { self.cycle = None; }
+
+    fn nicks(&self, st: &ServerState, buffer: &BufferId, prefix: &str) -> Vec<String> {
+        match buffer {
+            BufferId::Channel(name) => {
+                let Some(chan) = st.channels.values().find(|c| c.name.eq_ignore_ascii_case(name)) else { return Vec::new() };
+                let mut v: Vec<&String> = chan.users.iter().filter(|n| **n != st.nick && starts_with(n, prefix)).collect();
+                v.sort_by_key(|n| n.to_lowercase());
+                if self.cfg.sort == CompletionSort::LastSpoke {
+                    // stable, so nicks that never spoke stay alphabetical at the end
+                    v.sort_by_key(|n| std::cmp::Reverse(chan.last_spoke.get(*n)));
+                }
+                v.into_iter().cloned().collect()
+            }
+            BufferId::Query(nick) if starts_with(nick, prefix) => vec![nick.clone()],
+            _ => Vec::new(),
+        }
+    }
+}
+
+fn starts_with(s: &str, prefix: &str) -> bool {
+    s.get(..prefix.len()).is_some_and(|p| p.eq_ignore_ascii_case(prefix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proto::Message;
+
+    #[test]
+    fn completes_nicks_from_names() {
+        let engine = crate::Engine::new("net", "me");
+        engine.connected("me");
+        for line in [":me!u@h JOIN #c", ":s 353 me = #c :@me alice +bob", ":bob!b@h PRIVMSG #c :hi"] {
+            engine.on_message(Message::parse(line).unwrap());
+        }
+        let chan = BufferId::Channel("#c".into());
+        let c = engine.complete(&chan, "al", 2).unwrap();
+        assert_eq!((c.line.as_str(), c.cursor), ("alice: ", 7));
+        // not at the start of the line: a plain space instead of the suffix
+        assert_eq!(engine.complete(&chan, "hi +b", 5), None);
+        assert_eq!(engine.complete(&chan, "hi b", 4).unwrap().line, "hi bob ");
+    }
+}
@@ -0,0 +1,158 @@
+// Highlight rules: our nick, HexChat's irc_extra_hilight / irc_nick_hilight /
+// irc_no_hilight lists, and scoped word, regex and sender rules on top. A
+// match says which rule fired and where, so frontends can colour the words.
+use crate::{sender_matches, strip_with_offsets, wildmatch, wildmatch_case};
+use regex::{Regex, RegexBuilder};
+use std::ops::Range;
+
+#[derive(Debug, Clone)]
+pub enum Pattern {
+    /// A whole word; `*` and `?` wildcards allowed.
+    Word(String),
+    /// Searched anywhere in the text; case handling is fixed when compiled, see `Pattern::regex`.
+    Regex(Regex),
+    /// Every message from a matching sender; `$a:account` matches by account.
+    Sender(String),
+}
+
+impl Pattern {
+    pub fn regex(src: &str, case_sensitive: bool) -> Result<Self, regex::Error> {
+        Ok(Pattern::Regex(RegexBuilder::new(src).case_insensitive(!case_sensitive).build()?))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HighlightRule {
+    pub pattern: Pattern,
+    /// For `Word` and `Sender` patterns.
+    pub case_sensitive: bool,
+    /// Network names this rule applies on, wildcards allowed; empty: all.
+    pub networks: Vec<String>,
+    /// Channels (or query nicks) it applies in, wildcards allowed; empty: all.
+    pub channels: Vec<String>,
+    /// A match suppresses the highlight instead, like irc_no_hilight.
+    pub exclude: bool,
+}
+
+impl HighlightRule {
+    pub fn new(pattern: Pattern) -> Self {
+        Self { pattern, case_sensitive: false, networks: Vec::new(), channels: Vec::new(), exclude: false }
+    }
+
+    fn applies(&self, network: &str, buffer: Option<&str>) -> bool {
+        (self.networks.is_empty() || self.networks.iter().any(|n| wildmatch(n, network)))
+            && (self.channels.is_empty() || buffer.is_some_and(|b| self.channels.iter().any(|c| wildmatch(c, b))))
+    }
+
+    /// Where the rule matches; `Some(vec![])` for a sender match.
+    fn find(&self, from: &str, account: Option<&str>, text: &str) -> Option<Vec<Range<usize>>> {
+        let ranges = match &self.pattern {
+            Pattern::Word(w) => word_matches(text, |word| if self.case_sensitive { wildmatch_case(w, word) } else { wildmatch(w, word) }),
+            Pattern::Regex(re) => re.find_iter(text).filter(|m| !m.is_empty()).map(|m| m.range()).collect(),
+            Pattern::Sender(s) if self.case_sensitive && !s.starts_with("$a") => return wildmatch_case(s, from).then(Vec::new),
+            Pattern::Sender(s) => return sender_matches(s, from, account).then(Vec::new),
+        };
+        (!ranges.is_empty()).then_some(ranges)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct HighlightConfig {
+    /// Extra words that highlight like our nick does (irc_extra_hilight); wildcards allowed.
+    pub extra_words: Vec<String>,
+    /// Senders whose every message highlights (irc_nick_hilight); `$a:account` matches by account.
+    pub nick_patterns: Vec<String>,
+    /// Senders that never highlight, e.g. bots (irc_no_hilight); same syntax as `nick_patterns`.
+    pub no_highlight: Vec<String>,
+    /// Checked after the lists above, in order.
+    pub rules: Vec<HighlightRule>,
+}
+
+/// What made a message a highlight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HighlightSource {
+    OwnNick,
+    /// Index into `extra_words`.
+    ExtraWord(usize),
+    /// Index into `nick_patterns`.
+    Nick(usize),
+    /// Index into `rules`.
+    Rule(usize),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Highlight {
+    pub source: HighlightSource,
+    /// Byte ranges of the matches in the message text, formatting codes
+    /// included; empty when the sender, not the text, matched.
+    pub ranges: Vec<Range<usize>>,
+}
+
+impl HighlightConfig {
+    pub fn is_highlight(&self, own_nick: &str, from: &str, account: Option<&str>, text: &str) -> bool {
+        self.check(own_nick, "", None, from, account, text).is_some()
+    }
+
+    /// The first of: our nick, extra words, highlight nicks, rules (in order)
+    /// that matches a message from `from` in `buffer` on `network`.
+    pub fn check(&self, own_nick: &str, network: &str, buffer: Option<&str>, from: &str, account: Option<&str>, text: &str) -> Option<Highlight> {
+        if self.no_highlight.iter().any(|m| sender_matches(m, from, account)) { return None; }
+        // formatting codes would otherwise glue colour digits onto words
+        let (plain, offsets) = strip_with_offsets(text);
+        let rules: Vec<(usize, &HighlightRule)> = self.rules.iter().enumerate().filter(|(_, r)| r.applies(network, buffer)).collect();
+        if rules.iter().any(|(_, r)| r.exclude && r.find(from, account, &plain).is_some()) { return None; }
+        let found = |source, ranges: Vec<Range<usize>>| Some(Highlight {
+            source,
+            ranges: ranges.into_iter().map(|r| offsets[r.start]..offsets[r.end - 1] + 1).collect(),
+        });
+
+        let own = word_matches(&plain, |w| wildmatch(own_nick, w));
+        if !own.is_empty() { return found(HighlightSource::OwnNick, own); }
+        for (i, m) in self.extra_words.iter().enumerate() {
+            let ranges = word_matches(&plain, |w| wildmatch(m, w));
+            if !ranges.is_empty() { return found(HighlightSource::ExtraWord(i), ranges); }
+        }
+        if let Some(i) = self.nick_patterns.iter().position(|m| sender_matches(m, from, account)) {
+            return found(HighlightSource::Nick(i), Vec::new());
+        }
+        rules.into_iter().filter(|(_, r)| !r.exclude)
+            .find_map(|(i, r)| r.find(from, account, &plain).map(|ranges| (i, ranges)))
+            .and_then(|(i, ranges)| found(HighlightSource::Rule(i), ranges))
+    }
+}
+
+// nick characters that may appear inside a word (RFC1459 <special>), as in alert_match_text
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || "-[]\\`^{}_|".contains(c)
+}
+
+/// Ranges of the words in `text` that `matches` accepts.
+fn word_matches(text: &str, matches: impl Fn(&str) -> bool) -> Vec<Range<usize>> {
+    let mut out = Vec::new();
+    let mut start = None;
+    for (i, c) in text.char_indices().chain(std::iter::once((text.len(), ' '))) {
+        match (is_word_char(c), start) {
+            (true, None) => start = Some(i),
+            (false, Some(s)) => {
+                if matches(&text[s..i]) { out.push(s..i); }
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges_point_into_the_formatted_text() {
+        let cfg = HighlightConfig::default();
+        let text = "hey \x0304,01Me\x03 and \x02mé\x02";
+        let h = cfg.check("me", "net", Some("#c"), "bob", None, text).unwrap();
+        assert_eq!(h.source, HighlightSource::OwnNick);
+        assert_eq!(h.ranges.iter().map(|r| &text[r.clone()]).collect::<Vec<_>>(), ["Me"]);
+    }
+}
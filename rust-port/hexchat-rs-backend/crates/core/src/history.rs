@@ -0,0 +1,281 @@
+// Input history, as in HexChat's history.c: lines the user submitted,
+// recalled with up/down, searched by prefix or incrementally (ctrl-r), and
+// optionally kept across restarts with passwords blanked out.
+use crate::scrollback::BufferId;
+use anyhow::Result;
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::path::PathBuf;
+
+/// Stands in for passwords in the history file.
+const REDACTED: &str = "********";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HistoryScope {
+    /// One history shared by every buffer.
+    Global,
+    #[default]
+    PerBuffer,
+}
+
+#[derive(Debug, Clone)]
+pub struct HistoryConfig {
+    /// Lines kept per history (HexChat's HISTORY_SIZE).
+    pub max_lines: usize,
+    pub scope: HistoryScope,
+    /// File the history is loaded from and saved to; `None` keeps it in memory only.
+    pub path: Option<PathBuf>,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self { Self { max_lines: 100, scope: HistoryScope::PerBuffer, path: None } }
+}
+
+#[derive(Debug, Default)]
+struct Lines {
+    lines: VecDeque<String>,
+    /// Index being shown while navigating; `None` at the bottom (the line being typed).
+    pos: Option<usize>,
+    /// What was typed before the user started going up.
+    draft: String,
+}
+
+#[derive(Debug)]
+struct Search {
+    key: BufferId,
+    query: String,
+    /// Index of the current match.
+    at: Option<usize>,
+}
+
+#[derive(Debug, Default)]
+pub struct InputHistory {
+    cfg: HistoryConfig,
+    lists: HashMap<BufferId, Lines>,
+    search: Option<Search>,
+}
+
+impl InputHistory {
+    /// Starts from the history file in `cfg.path`, if any.
+    pub fn open(cfg: HistoryConfig) -> Result<Self> {
+        let mut h = Self { cfg, ..Self::default() };
+        let Some(path) = &h.cfg.path else { return Ok(h) };
+        if !path.exists() { return Ok(h); }
+        for raw in fs::read_to_string(path)?.lines() {
+            if let Some((id, line)) = parse_entry(raw) {
+                let key = h.key(&id);
+                h.push(key, line);
+            }
+        }
+        Ok(h)
+    }
+
+    /// Writes the history file, secrets redacted.
+    pub fn save(&self) -> Result<()> {
+        let Some(path) = &self.cfg.path else { return Ok(()) };
+        if let Some(parent) = path.parent() { fs::create_dir_all(parent)?; }
+        let mut out = String::new();
+        for (id, list) in &self.lists {
+            for line in &list.lines {
+                let (kind, name) = match id {
+                    BufferId::Server => ("S", ""),
+                    BufferId::Channel(n) => ("C", n.as_str()),
+                    BufferId::Query(n) => ("Q", n.as_str()),
+                };
+                out.push_str(&format!("{}\t{}\t{}\n", kind, name, escape(&redact(line))));
+            }
+        }
+        fs::write(path, out)?;
+        Ok(())
+    }
+
+    pub fn config(&self) -> &HistoryConfig { &self.cfg }
+
+    /// Records a submitted line and ends any navigation or search in progress.
+    pub fn add(&mut self, id: &BufferId, line: &str) {
+        if line.trim().is_empty() { return; }
+        self.search = None;
+        let key = self.key(id);
+        let list = self.lists.entry(key.clone()).or_default();
+        list.pos = None;
+        list.draft.clear();
+        // repeating a line does not push the older ones out
+        if list.lines.back().is_some_and(|l| l == line) { return; }
+        self.push(key, line.to_string());
+    }
+
+    /// Previous (older) line; `current` is what is in the input box, given back by `down` at the bottom.
+    pub fn up(&mut self, id: &BufferId, current: &str) -> Option<&str> {
+        let list = self.lists.get_mut(&self.key(id))?;
+        let pos = match list.pos {
+            None if list.lines.is_empty() => return None,
+            None => { list.draft = current.to_string(); list.lines.len() - 1 }
+            Some(0) => 0,
+            Some(p) => p - 1,
+        };
+        list.pos = Some(pos);
+        list.lines.get(pos).map(String::as_str)
+    }
+
+    /// Next (newer) line, or the line that was being typed once past the newest.
+    pub fn down(&mut self, id: &BufferId) -> Option<&str> {
+        let list = self.lists.get_mut(&self.key(id))?;
+        let pos = list.pos?;
+        if pos + 1 < list.lines.len() {
+            list.pos = Some(pos + 1);
+            return list.lines.get(pos + 1).map(String::as_str);
+        }
+        list.pos = None;
+        Some(&list.draft)
+    }
+
+    /// Lines starting with `prefix`, newest first, without repeats.
+    pub fn prefix_search(&self, id: &BufferId, prefix: &str) -> Vec<&str> {
+        let Some(list) = self.lists.get(&self.key(id)) else { return Vec::new() };
+        let mut out: Vec<&str> = Vec::new();
+        for l in list.lines.iter().rev().filter(|l| l.starts_with(prefix)) {
+            if !out.contains(&l.as_str()) { out.push(l); }
+        }
+        out
+    }
+
+    /// Reverse-incremental search: the newest line containing `query`. While a
+    /// search is running, a longer `query` keeps the current match if it still fits.
+    pub fn search(&mut self, id: &BufferId, query: &str) -> Option<&str> {
+        let key = self.key(id);
+        let from = match &self.search {
+            Some(s) if s.key == key && query.starts_with(s.query.as_str()) => s.at,
+            _ => None,
+        };
+        let list = self.lists.get(&key);
+        let at = list.and_then(|l| find_back(&l.lines, query, from.map_or(l.lines.len(), |a| a + 1)));
+        self.search = Some(Search { key, query: query.to_string(), at });
+        list?.lines.get(at?).map(String::as_str)
+    }
+
+    /// The next older match for the running search.
+    pub fn search_next(&mut self, id: &BufferId) -> Option<&str> {
+        let key = self.key(id);
+        let s = self.search.as_mut().filter(|s| s.key == key)?;
+        let lines = &self.lists.get(&key)?.lines;
+        s.at = find_back(lines, &s.query, s.at?).or(s.at);
+        lines.get(s.at?).map(String::as_str)
+    }
+
+    pub fn end_search(&mut self) { self.search = None; }
+
+    pub fn clear(&mut self, id: &BufferId) {
+        let key = self.key(id);
+        self.lists.remove(&key);
+    }
+
+    fn key(&self, id: &BufferId) -> BufferId {
+        match self.cfg.scope {
+            HistoryScope::Global => BufferId::Server,
+            HistoryScope::PerBuffer => id.clone(),
+        }
+    }
+
+    fn push(&mut self, key: BufferId, line: String) {
+        let list = self.lists.entry(key).or_default();
+        list.lines.push_back(line);
+        while list.lines.len() > self.cfg.max_lines.max(1) { list.lines.pop_front(); }
+    }
+}
+
+/// Newest line before index `before` containing `query`.
+fn find_back(lines: &VecDeque<String>, query: &str, before: usize) -> Option<usize> {
+    lines.iter().take(before).rposition(|l| l.contains(query))
+}
+
+/// Blanks out the password in lines like `/msg NickServ IDENTIFY secret`,
+/// `/ns ghost nick secret`, `/oper name secret` or `/quote PASS secret`.
+pub fn redact(line: &str) -> Cow<'_, str> {
+    let words: Vec<&str> = line.split(' ').collect();
+    let word = |i: usize| words.get(i).map(|w| w.to_ascii_lowercase()).unwrap_or_default();
+    if !line.starts_with('/') { return Cow::Borrowed(line); }
+    let cmd = word(0)[1..].to_string();
+    let service = |w: &str| ["nickserv", "chanserv", "q", "authserv", "userserv"].iter().any(|s| w == *s || w.starts_with(&format!("{}@", s)));
+    // index of the first word to hide
+    // the service command may start the trailing parameter (`:IDENTIFY`)
+    let secret_from = |svc_cmd: usize| match word(svc_cmd).trim_start_matches(':') {
+        "identify" | "id" | "login" | "auth" | "register" | "ghost" | "regain" | "recover" | "release" | "group" => Some(svc_cmd + 1),
+        "set" if word(svc_cmd + 1) == "password" || word(svc_cmd + 1) == "pass" => Some(svc_cmd + 2),
+        _ => None,
+    };
+    let from = match cmd.as_str() {
+        "msg" | "privmsg" | "query" if service(&word(1)) => secret_from(2),
+        "ns" | "nickserv" | "cs" | "chanserv" | "q" | "authserv" => secret_from(1),
+        "pass" => Some(1),
+        "oper" => Some(2),
+        "quote" | "raw" => match word(1).as_str() {
+            "pass" | "authenticate" => Some(2),
+            "oper" => Some(3),
+            "privmsg" if service(&word(2)) => secret_from(3),
+            _ => None,
+        },
+        _ => None,
+    };
+    match from {
+        Some(i) if i < words.len() => Cow::Owned(format!("{} {}", words[..i].join(" "), REDACTED)),
+        _ => Cow::Borrowed(line),
+    }
+}
+
+fn parse_entry(raw: &str) -> Option<(BufferId, String)> {
+    let mut parts = raw.splitn(3, '\t');
+    let (kind, name, line) = (parts.next()?, parts.next()?, parts.next()?);
+    let id = match kind {
+        "S" => BufferId::Server,
+        "C" => BufferId::Channel(name.to_string()),
+        "Q" => BufferId::Query(name.to_string()),
+        _ => return None,
+    };
+    Some((id, unescape(line)))
+}
+
+// pasted input may hold line breaks; the file has one entry per line
+fn escape(line: &str) -> String { line.replace('\\', "\\\\").replace('\n', "\\n") }
+
+fn unescape(line: &str) -> String {
+    let mut out = String::with_capacity(line.len());
+    let mut it = line.chars();
+    while let Some(c) = it.next() {
+        if c != '\\' { out.push(c); continue; }
+        match it.next() {
+            Some('n') => out.push('\n'),
+            Some(o) => out.push(o),
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_service_passwords() {
+        for (line, want) in [
+            ("/msg NickServ IDENTIFY hunter2", "/msg NickServ IDENTIFY ********"),
+            ("/msg NickServ :IDENTIFY hunter2", "/msg NickServ :IDENTIFY ********"),
+            ("/quote PRIVMSG NickServ :IDENTIFY hunter2", "/quote PRIVMSG NickServ :IDENTIFY ********"),
+            ("/msg ChanServ IDENTIFY #c pw", "/msg ChanServ IDENTIFY ********"),
+            ("/ns set password abc", "/ns set password ********"),
+            ("/oper me pw", "/oper me ********"),
+            ("/quote PASS x", "/quote PASS ********"),
+            ("/msg bob identify x", "/msg bob identify x"),
+            ("identify hunter2", "identify hunter2"),
+        ] {
+            assert_eq!(redact(line), want, "{}", line);
+        }
+    }
+
+    #[test]
+    fn escapes_line_breaks() {
+        let line = "a\\nb\nc";
+        assert_eq!(unescape(&escape(line)), line);
+    }
+}
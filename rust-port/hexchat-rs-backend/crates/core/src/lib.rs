@@ -1,16 +1,143 @@
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use proto::Message;
+use tokio::sync::oneshot;
+
+pub mod activity;
+pub mod away;
+pub mod batch;
+pub mod bouncer;
+pub mod chanlist;
+pub mod completion;
+pub mod highlight;
+pub mod history;
+pub mod modes;
+pub mod multiline;
+pub mod netsplit;
+pub mod scrollback;
+pub mod typing;
+pub mod urls;
+pub mod whois;
+pub use activity::{Activity, ActivityTracker, BufferActivity};
+pub use away::AwayConfig;
+use away::{AwayTracker, IdleAction};
+pub use batch::{Batch, BatchItem};
+use batch::{BatchTracker, Handled};
+pub use bouncer::BouncerNetwork;
+use bouncer::BouncerNetworks;
+pub use chanlist::{ChannelList, ListEntry, ListFilter};
+pub use completion::{Completer, Completion, CompletionConfig, CompletionSort};
+pub use highlight::{Highlight, HighlightConfig, HighlightRule, HighlightSource, Pattern};
+pub use history::{HistoryConfig, HistoryScope, InputHistory};
+pub use modes::{BanType, ListMode, MaskEntry, ModeChange};
+use netsplit::{NetsplitTracker, Settled};
+pub use scrollback::{BufferId, Line, Scrollback, ScrollbackConfig};
+pub use typing::TypingState;
+use typing::TypingTracker;
+pub use urls::{GrabbedUrl, UrlGrabber, UrlGrabberConfig, UrlKind};
+pub use whois::{WhoReply, WhoResult, WhoisResult};
+use whois::{Completed, InfoQueries};
+
+/// Removes mIRC colour and formatting control codes.
+pub fn strip_formatting(text: &str) -> String { strip_with_offsets(text).0 }
+
+/// `strip_formatting`, plus the offset in `text` of every byte kept.
+pub(crate) fn strip_with_offsets(text: &str) -> (String, Vec<usize>) {
+    let mut out = String::with_capacity(text.len());
+    let mut offsets = Vec::with_capacity(text.len());
+    let mut it = text.char_indices().peekable();
+    while let Some((i, c)) = it.next() {
+        match c {
+            '\x02' | '\x0f' | '\x11' | '\x16' | '\x1d' | '\x1e' | '\x1f' => {}
+            '\x03' => {
+                for _ in 0..2 { if it.next_if(|(_, d)| d.is_ascii_digit()).is_none() { break; } }
+                if it.peek().map(|p| p.1) == Some(',') {
+                    it.next();
+                    for _ in 0..2 { if it.next_if(|(_, d)| d.is_ascii_digit()).is_none() { break; } }
+                }
+            }
+            c => {
+                out.push(c);
+                offsets.extend(i..i + c.len_utf8());
+            }
+        }
+    }
+    (out, offsets)
+}
+
+/// Case-insensitive IRC wildcard match: `*` matches any run, `?` any one character.
+pub fn wildmatch(pattern: &str, text: &str) -> bool {
+    let p: Vec<char> = pattern.chars().flat_map(char::to_lowercase).collect();
+    let t: Vec<char> = text.chars().flat_map(char::to_lowercase).collect();
+    wild(&p, &t)
+}
+
+/// `wildmatch` that tells case apart.
+pub fn wildmatch_case(pattern: &str, text: &str) -> bool {
+    wild(&pattern.chars().collect::<Vec<_>>(), &text.chars().collect::<Vec<_>>())
+}
+
+fn wild(p: &[char], t: &[char]) -> bool {
+    let (mut pi, mut ti) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while ti < t.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == t[ti]) {
+            pi += 1;
+            ti += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            star = Some((pi, ti));
+            pi += 1;
+        } else if let Some((sp, st)) = star {
+            pi = sp + 1;
+            ti = st + 1;
+            star = Some((sp, st + 1));
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|&c| c == '*')
+}
+
+/// Matches a sender against a nick wildcard, or against their services
+/// account with `$a:pattern` (`$a` alone: any logged-in user).
+pub fn sender_matches(pattern: &str, nick: &str, account: Option<&str>) -> bool {
+    match pattern.strip_prefix("$a") {
+        Some("") => account.is_some(),
+        Some(rest) => rest.strip_prefix(':').zip(account).is_some_and(|(m, a)| wildmatch(m, a)),
+        None => wildmatch(pattern, nick),
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
 pub struct ChannelId(pub String);
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Channel {
     pub name: String,
     pub users: HashSet<String>,
+    /// Cached ban/exception/invite-exception lists, once fetched.
+    #[serde(default)]
+    pub mask_lists: HashMap<ListMode, Vec<MaskEntry>>,
+    #[serde(skip)]
+    loading: HashSet<ListMode>,
+    /// When each member last said something, for nick completion.
+    #[serde(skip)]
+    pub last_spoke: HashMap<String, SystemTime>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct User {
+    pub nick: String,
+    pub user: Option<String>,
+    pub host: Option<String>,
+    pub realname: Option<String>,
+    pub account: Option<String>,
+    pub away: bool,
+    /// Known from away-notify or RPL_AWAY; WHO only tells us `away`.
+    pub away_message: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -18,11 +145,183 @@ pub struct ServerState {
     pub network: String,
     pub nick: String,
     pub channels: HashMap<ChannelId, Channel>,
+    /// Everyone we share a channel with, by nick.
+    pub users: HashMap<String, User>,
+    /// Our own away message while marked away.
+    pub away: Option<String>,
+    /// Round-trip time of the last answered lag PING.
+    pub lag_ms: Option<u64>,
+    /// RPL_ISUPPORT (005) tokens; valueless tokens map to "".
+    pub isupport: HashMap<String, String>,
+    /// Open private-message buffers, by the other user's nick.
+    pub queries: Vec<String>,
+    /// Capabilities enabled with CAP ACK, with their CAP LS values.
+    pub caps: HashMap<String, String>,
+}
+
+impl ServerState {
+    pub fn has_cap(&self, cap: &str) -> bool { self.caps.contains_key(cap) }
+
+    /// One `key=value` item of a cap's value, e.g. `max-bytes` of draft/multiline.
+    pub fn cap_param(&self, cap: &str, key: &str) -> Option<&str> {
+        self.caps.get(cap)?.split(',').find_map(|kv| {
+            let (k, v) = kv.split_once('=').unwrap_or((kv, ""));
+            (k == key).then_some(v)
+        })
+    }
+
+    /// Whether we may send client-only tag `tag` (without its `+`), per CLIENTTAGDENY.
+    pub fn client_tag_allowed(&self, tag: &str) -> bool {
+        if !self.has_cap("message-tags") { return false; }
+        let Some(deny) = self.isupport("CLIENTTAGDENY") else { return true };
+        let items: Vec<&str> = deny.split(',').collect();
+        if items.contains(&"*") { items.iter().any(|i| i.strip_prefix('-') == Some(tag)) } else { !items.contains(&tag) }
+    }
+
+    /// Longest text that fits in one `PRIVMSG <target> :` once the server
+    /// prepends our full prefix.
+    fn max_text_len(&self, target: &str) -> usize {
+        let me = self.users.get(&self.nick);
+        // 63 bytes of user@host when we do not know ours
+        let prefix = self.nick.len() + me.and_then(|u| Some(u.user.as_ref()?.len() + u.host.as_ref()?.len())).unwrap_or(63) + 4;
+        512usize.saturating_sub(2 + prefix + "PRIVMSG  :".len() + target.len()).max(1)
+    }
+
+    pub fn isupport(&self, key: &str) -> Option<&str> { self.isupport.get(key).map(String::as_str) }
+
+    /// Whether `name` is a channel per CHANTYPES, ignoring any STATUSMSG prefix (`@#chan`).
+    pub fn is_channel(&self, name: &str) -> bool {
+        let name = self.strip_statusmsg(name);
+        let chantypes = self.isupport("CHANTYPES").unwrap_or("#&");
+        name.chars().next().is_some_and(|c| chantypes.contains(c))
+    }
+
+    pub fn strip_statusmsg<'a>(&self, target: &'a str) -> &'a str {
+        let prefixes = self.isupport("STATUSMSG").unwrap_or("");
+        target.trim_start_matches(|c| prefixes.contains(c))
+    }
+
+    /// The services account `nick` is logged in as, when known.
+    pub fn account_of(&self, nick: &str) -> Option<&str> {
+        self.users.get(nick).and_then(|u| u.account.as_deref())
+    }
+
+    fn shares_channel(&self, nick: &str) -> bool {
+        self.channels.values().any(|c| c.users.contains(nick))
+    }
+
+    fn apply_whois(&mut self, r: &WhoisResult) {
+        let Some(u) = self.users.get_mut(&r.nick) else { return };
+        if r.user.is_some() { u.user = r.user.clone(); }
+        if r.host.is_some() { u.host = r.host.clone(); }
+        if r.realname.is_some() { u.realname = r.realname.clone(); }
+        if r.account.is_some() { u.account = r.account.clone(); }
+        u.away = r.away.is_some();
+        u.away_message = r.away.clone();
+    }
+
+    fn apply_who(&mut self, r: &WhoReply) {
+        if !self.users.contains_key(&r.nick) && !self.shares_channel(&r.nick) { return; }
+        let u = self.users.entry(r.nick.clone()).or_insert_with(|| User { nick: r.nick.clone(), ..Default::default() });
+        u.user = Some(r.user.clone());
+        u.host = Some(r.host.clone());
+        u.realname = Some(r.realname.clone());
+        if r.account.is_some() { u.account = r.account.clone(); }
+        u.away = r.away();
+        if !u.away { u.away_message = None; }
+    }
+
+    fn modes_per_line(&self) -> usize {
+        self.isupport("MODES").and_then(|m| m.parse().ok()).unwrap_or(3)
+    }
+
+    pub fn find_query(&self, nick: &str) -> Option<&String> {
+        self.queries.iter().find(|q| q.eq_ignore_ascii_case(nick))
+    }
+
+    /// The CHATHISTORY command for `target`, or `None` when the server does not offer it
+    /// or did not ACK the cap.
+    fn history_command(&self, target: &str, req: HistoryRequest, limit: usize) -> Option<String> {
+        if !self.has_cap("draft/chathistory") { return None; }
+        // CHATHISTORY=<max>, 0 meaning no limit
+        let limit = match self.isupport("CHATHISTORY")?.parse::<usize>() {
+            Ok(max) if max > 0 => limit.min(max),
+            _ => limit,
+        };
+        let ts = |t| format!("timestamp={}", proto::format_server_time(t));
+        Some(match req {
+            HistoryRequest::Latest => format!("CHATHISTORY LATEST {} * {}", target, limit),
+            HistoryRequest::Before(t) => format!("CHATHISTORY BEFORE {} {} {}", target, ts(t), limit),
+            HistoryRequest::After(t) => format!("CHATHISTORY AFTER {} {} {}", target, ts(t), limit),
+            HistoryRequest::Between(a, b) => format!("CHATHISTORY BETWEEN {} {} {} {}", target, ts(a), ts(b), limit),
+        })
+    }
+
+    /// Buffer a PRIVMSG/NOTICE from `from` to `target` belongs to: the channel,
+    /// or the query with whoever is on the other end (for our own echoed messages, the target).
+    pub fn buffer_for(&self, from: &str, target: &str) -> BufferId {
+        if self.is_channel(target) {
+            BufferId::Channel(self.strip_statusmsg(target).to_string())
+        } else if target.eq_ignore_ascii_case(&self.nick) && !from.eq_ignore_ascii_case(&self.nick) {
+            BufferId::Query(self.find_query(from).cloned().unwrap_or_else(|| from.to_string()))
+        } else {
+            BufferId::Query(self.find_query(target).cloned().unwrap_or_else(|| target.to_string()))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PingConfig {
+    /// How often to send our own PING when the link is idle.
+    pub interval: Duration,
+    /// Silence after which the link is considered dead (HexChat's net_ping_timeout).
+    pub timeout: Duration,
+}
+
+impl Default for PingConfig {
+    fn default() -> Self { Self { interval: Duration::from_secs(30), timeout: Duration::from_secs(60) } }
+}
+
+struct Liveness {
+    cfg: PingConfig,
+    last_rx: Instant,
+    last_ping: Instant,
+    lag_token: Option<(String, Instant)>,
 }
 
 #[derive(Clone)]
 pub struct Engine {
     inner: Arc<RwLock<ServerState>>,
+    outgoing: Arc<Mutex<Vec<String>>>,
+    live: Arc<Mutex<Liveness>>,
+    scrollback: Arc<Mutex<Scrollback>>,
+    activity: Arc<Mutex<ActivityTracker>>,
+    netsplit: Arc<Mutex<NetsplitTracker>>,
+    info: Arc<Mutex<InfoQueries>>,
+    chanlist: Arc<Mutex<ChannelList>>,
+    away: Arc<Mutex<AwayTracker>>,
+    batches: Arc<Mutex<BatchTracker>>,
+    labels: Arc<Mutex<Labels>>,
+    typing: Arc<Mutex<TypingTracker>>,
+    bouncer: Arc<Mutex<BouncerNetworks>>,
+    completer: Arc<Mutex<Completer>>,
+    urls: Arc<Mutex<UrlGrabber>>,
+}
+
+/// Commands sent with a `label` tag, waiting for their labeled-response.
+#[derive(Debug, Default)]
+struct Labels {
+    next: u64,
+    pending: HashMap<String, oneshot::Sender<LabeledResponse>>,
+}
+
+/// The server's answer to a labeled command.
+#[derive(Debug, Clone)]
+pub enum LabeledResponse {
+    /// ACK: handled, nothing else to say.
+    Ack,
+    /// A single reply, or the whole labeled-response batch as `Event::Batch`.
+    Event(Box<TimedEvent>),
 }
 
 #[derive(Debug, Clone)]
@@ -30,63 +329,980 @@ pub enum Event {
     Welcome(String),
     Join { nick: String, channel: String },
     Part { nick: String, channel: String },
-    PrivMsg { from: String, target: String, text: String },
-    Notice { from: String, target: String, text: String },
+    Quit { nick: String, reason: String, channels: Vec<String> },
+    /// One per split server pair, once its QUIT burst is over.
+    Netsplit { servers: (String, String), nicks: Vec<String>, by_channel: BTreeMap<String, Vec<String>> },
+    /// Split nicks that came back, collapsed like `Netsplit`.
+    Netjoin { servers: (String, String), nicks: Vec<String>, by_channel: BTreeMap<String, Vec<String>> },
+    /// `account` is the sender's services account, from account-tag or the user table;
+    /// `reply_to` the msgid this answers (+draft/reply); `highlight` what in it, if anything, highlights us.
+    PrivMsg { from: String, account: Option<String>, target: String, text: String, reply_to: Option<String>, highlight: Option<Highlight> },
+    Notice { from: String, account: Option<String>, target: String, text: String, highlight: Option<Highlight> },
+    /// A user logged in to (`Some`) or out of services, via account-notify.
+    AccountChange { nick: String, account: Option<String> },
+    /// chghost: new ident and host, in place of a QUIT/JOIN cycle.
+    HostChange { nick: String, user: String, host: String },
+    /// setname: new realname.
+    RealnameChange { nick: String, realname: String },
+    /// `by` invited `nick` to `channel`; for others only seen with invite-notify.
+    Invite { by: String, nick: String, channel: String },
+    /// +typing; a `Done` is also sent when a notification times out.
+    Typing { nick: String, target: String, state: TypingState },
+    /// A bouncer network was added or changed (`Some`) or removed (soju.im/bouncer-networks).
+    BouncerNetwork { id: String, network: Option<BouncerNetwork> },
+    /// Another client of ours read `target` up to `time` (draft/read-marker); `None`: never read.
+    ReadMarker { target: String, time: Option<SystemTime> },
+    /// +draft/react to the message with `msgid`.
+    Reaction { nick: String, target: String, msgid: String, reaction: String },
     Topic { channel: String, text: String },
+    Mode { target: String, by: String, changes: Vec<ModeChange> },
+    /// Someone else went away (`Some`) or came back, via away-notify.
+    Away { nick: String, message: Option<String> },
+    /// RPL_AWAY after messaging an away user; repeats are suppressed per away_show_once.
+    AwayReply { nick: String, message: String },
+    /// The server confirmed our own away (306) or return (305).
+    SelfAway { message: Option<String> },
+    /// A fetched ban (367/368), exception (348/349) or invite-exception (346/347) list.
+    MaskList { channel: String, mode: ListMode, entries: Vec<MaskEntry> },
+    Whois(WhoisResult),
+    WhoWas(WhoisResult),
+    Who(WhoResult),
+    /// A LIST reply that passed the active filter; `count` is the running total
+    /// of those, `received` of every reply so far.
+    ListEntry { entry: ListEntry, count: usize, received: usize },
+    /// A LIST reply the filter dropped, so progress still shows.
+    ListProgress { count: usize, received: usize },
+    ListEnd { count: usize, received: usize },
+    Nick { old: String, new: String },
+    MonitorOnline(Vec<String>),
+    MonitorOffline(Vec<String>),
+    Ping(String),
+    /// A closed BATCH and what its lines turned into. netsplit and netjoin
+    /// batches come out as `Netsplit`/`Netjoin` instead.
+    Batch { kind: String, params: Vec<String>, events: Vec<TimedEvent> },
+    LagUpdate { lag_ms: u64 },
+    PingTimeout,
     Unknown(Message),
 }
 
+/// An event and when it happened: the server-time `time` tag when present
+/// (bouncer playback), otherwise when we received it.
+#[derive(Debug, Clone)]
+pub struct TimedEvent {
+    pub time: SystemTime,
+    /// The IRCv3 `msgid` tag of the line behind the event.
+    pub msgid: Option<String>,
+    pub event: Event,
+}
+
+/// A CHATHISTORY query, relative to a point in time.
+#[derive(Debug, Clone, Copy)]
+pub enum HistoryRequest {
+    Latest,
+    Before(SystemTime),
+    After(SystemTime),
+    Between(SystemTime, SystemTime),
+}
+
+/// Lines asked for per CHATHISTORY request, unless the server allows fewer.
+const HISTORY_PAGE: usize = 100;
+
 impl Engine {
     pub fn new(network: impl Into<String>, nick: impl Into<String>) -> Self {
+        let network = network.into();
+        let scrollback = Scrollback::new(network.clone(), ScrollbackConfig::default());
         let state = ServerState {
-            network: network.into(),
+            network,
             nick: nick.into(),
             channels: HashMap::new(),
+            users: HashMap::new(),
+            away: None,
+            lag_ms: None,
+            isupport: HashMap::new(),
+            queries: Vec::new(),
+            caps: HashMap::new(),
         };
-        Self { inner: Arc::new(RwLock::new(state)) }
+        let now = Instant::now();
+        let live = Liveness { cfg: PingConfig::default(), last_rx: now, last_ping: now, lag_token: None };
+        Self {
+            inner: Arc::new(RwLock::new(state)),
+            outgoing: Arc::new(Mutex::new(Vec::new())),
+            live: Arc::new(Mutex::new(live)),
+            scrollback: Arc::new(Mutex::new(scrollback)),
+            activity: Arc::new(Mutex::new(ActivityTracker::default())),
+            netsplit: Arc::new(Mutex::new(NetsplitTracker::default())),
+            info: Arc::new(Mutex::new(InfoQueries::default())),
+            chanlist: Arc::new(Mutex::new(ChannelList::default())),
+            away: Arc::new(Mutex::new(AwayTracker::default())),
+            batches: Arc::new(Mutex::new(BatchTracker::default())),
+            labels: Arc::new(Mutex::new(Labels::default())),
+            typing: Arc::new(Mutex::new(TypingTracker::default())),
+            bouncer: Arc::new(Mutex::new(BouncerNetworks::default())),
+            completer: Arc::new(Mutex::new(Completer::default())),
+            urls: Arc::new(Mutex::new(UrlGrabber::default())),
+        }
     }
 
     pub fn state(&self) -> ServerState { self.inner.read().clone() }
 
-    pub fn on_message(&self, msg: Message) -> Event {
+    pub fn scrollback(&self) -> parking_lot::MutexGuard<'_, Scrollback> { self.scrollback.lock() }
+
+    pub fn activity(&self) -> parking_lot::MutexGuard<'_, ActivityTracker> { self.activity.lock() }
+
+    /// Links seen in messages so far.
+    pub fn urls(&self) -> parking_lot::MutexGuard<'_, UrlGrabber> { self.urls.lock() }
+
+    /// Whether a message from `from` to `target` highlights us, per the configured highlight rules.
+    pub fn highlight(&self, from: &str, target: &str, text: &str) -> Option<Highlight> {
+        let st = self.inner.read();
+        self.highlight_in(&st, from, st.account_of(from), target, text)
+    }
+
+    fn highlight_in(&self, st: &ServerState, from: &str, account: Option<&str>, target: &str, text: &str) -> Option<Highlight> {
+        if from == st.nick { return None; }
+        let buf = st.buffer_for(from, target);
+        self.activity.lock().config().check(&st.nick, &st.network, buf.name(), from, account, text)
+    }
+
+    /// Clears a buffer's unread state and, with draft/read-marker, tells our
+    /// other clients how far we have read.
+    pub fn mark_read(&self, id: &BufferId) {
+        let sync = self.inner.read().has_cap("draft/read-marker");
+        let newest = self.scrollback.lock().newest(id).map(|l| l.time);
+        let mut act = self.activity.lock();
+        act.mark_read(id);
+        let (Some(time), Some(target)) = (newest, id.name()) else { return };
+        if act.set_read_marker(id, time) && sync {
+            self.send(format!("MARKREAD {} timestamp={}", target, proto::format_server_time(time)));
+        }
+    }
+
+    /// Sends WHOIS; the replies come back as one `Event::Whois`.
+    pub fn whois(&self, nick: &str) { self.send(format!("WHOIS {}", nick)); }
+
+    pub fn whowas(&self, nick: &str) { self.send(format!("WHOWAS {}", nick)); }
+
+    /// Sends WHO, as WHOX with a query token when the server supports it; replies come back as one `Event::Who`.
+    pub fn who(&self, mask: &str) {
+        let whox = self.inner.read().isupport("WHOX").is_some();
+        let line = self.info.lock().who_command(mask, whox);
+        self.send(line);
+    }
+
+    /// Starts a /list, pushing what it can of `filter` to the server via ELIST.
+    pub fn list(&self, filter: ListFilter) {
+        let elist = self.inner.read().isupport("ELIST").unwrap_or("").to_string();
+        let line = filter.command(&elist);
+        self.chanlist.lock().start(filter);
+        self.send(line);
+    }
+
+    pub fn cancel_list(&self) { self.chanlist.lock().cancel(); }
+
+    /// The channel directory from the last (or running) LIST.
+    pub fn channel_list(&self) -> parking_lot::MutexGuard<'_, ChannelList> { self.chanlist.lock() }
+
+    /// Marks us away with `message`, or back with `None`.
+    pub fn set_away(&self, message: Option<&str>) {
+        self.away.lock().sent_away(message, false);
+        match message {
+            Some(m) => self.send(format!("AWAY :{}", m)),
+            None => self.send("AWAY"),
+        }
+    }
+
+    pub fn set_away_config(&self, cfg: AwayConfig) { self.away.lock().cfg = cfg; }
+
+    /// The frontend's idle time (no input); drives auto-away and the automatic return.
+    pub fn report_idle(&self, idle: Duration) {
+        let away = self.inner.read().away.is_some();
+        let mut tracker = self.away.lock();
+        match tracker.idle(idle, away) {
+            Some(IdleAction::GoAway(reason)) => {
+                tracker.sent_away(Some(&reason), true);
+                self.send(format!("AWAY :{}", reason));
+            }
+            Some(IdleAction::Return) => {
+                tracker.sent_away(None, false);
+                self.send("AWAY");
+            }
+            None => {}
+        }
+    }
+
+    /// Asks the server for a channel's ban, exception or invite-exception list.
+    pub fn fetch_mask_list(&self, channel: &str, mode: ListMode) {
+        self.send(format!("MODE {} +{}", channel, mode.mode_char()));
+    }
+
+    /// Ban mask for a nick we know the ident and host of.
+    pub fn ban_mask_for(&self, nick: &str, ty: BanType) -> Option<String> {
+        let st = self.inner.read();
+        let u = st.users.get(nick)?;
+        Some(modes::ban_mask(u.user.as_deref()?, u.host.as_deref()?, ty))
+    }
+
+    /// Bans a nick (turned into a mask per `ty`) or a literal mask.
+    pub fn ban(&self, channel: &str, nick_or_mask: &str, ty: BanType) {
+        let mask = self.ban_mask_for(nick_or_mask, ty).unwrap_or_else(|| nick_or_mask.to_string());
+        self.send(format!("MODE {} +b {}", channel, mask));
+    }
+
+    pub fn unban(&self, channel: &str, masks: &[String]) {
+        let per_line = self.inner.read().modes_per_line();
+        for line in modes::mode_lines(channel, false, 'b', masks, per_line) { self.send(line); }
+    }
+
+    /// Removes every ban in the cached list; returns how many were sent.
+    pub fn mass_unban(&self, channel: &str) -> usize {
+        let masks: Vec<String> = self.inner.read().channels.get(&ChannelId(channel.to_string()))
+            .and_then(|c| c.mask_lists.get(&ListMode::Ban))
+            .map(|l| l.iter().map(|e| e.mask.clone()).collect())
+            .unwrap_or_default();
+        self.unban(channel, &masks);
+        masks.len()
+    }
+
+    /// Opens (or returns the existing) query buffer with `nick`, as /query does.
+    pub fn open_query(&self, nick: &str) -> BufferId {
+        let mut st = self.inner.write();
+        if let Some(q) = st.find_query(nick) { return BufferId::Query(q.clone()); }
+        st.queries.push(nick.to_string());
+        BufferId::Query(nick.to_string())
+    }
+
+    pub fn close_query(&self, nick: &str) {
+        self.inner.write().queries.retain(|q| !q.eq_ignore_ascii_case(nick));
+    }
+
+    pub fn set_ping_config(&self, cfg: PingConfig) { self.live.lock().cfg = cfg; }
+
+    /// Resets per-connection state after (re)registering as `nick`.
+    pub fn connected(&self, nick: impl Into<String>) {
         let mut st = self.inner.write();
+        st.nick = nick.into();
+        st.channels.clear();
+        st.users.clear();
+        st.away = None;
+        st.lag_ms = None;
+        st.isupport.clear();
+        st.caps.clear();
+        self.batches.lock().clear();
+        self.bouncer.lock().clear();
+        // the old connection will never answer these
+        self.labels.lock().pending.clear();
+        let mut live = self.live.lock();
+        let now = Instant::now();
+        live.last_rx = now;
+        live.last_ping = now;
+        live.lag_token = None;
+    }
+
+    /// Capabilities negotiated during registration, as `name` or `name=value`.
+    pub fn set_caps(&self, caps: impl IntoIterator<Item = String>) {
+        self.inner.write().caps = caps.into_iter().map(|c| match c.split_once('=') {
+            Some((name, value)) => (name.to_string(), value.to_string()),
+            None => (c, String::new()),
+        }).collect();
+    }
+
+    /// Sends a raw command, labeled when labeled-response is enabled. The
+    /// receiver resolves with whatever the server answers to exactly this command;
+    /// `None` means the server cannot tell us.
+    pub fn send_labeled(&self, line: &str) -> Option<oneshot::Receiver<LabeledResponse>> {
+        if !self.inner.read().has_cap("labeled-response") {
+            self.send(line);
+            return None;
+        }
+        let (tx, rx) = oneshot::channel();
+        let mut labels = self.labels.lock();
+        labels.next += 1;
+        let label = format!("hc{}", labels.next);
+        match line.strip_prefix('@') {
+            Some(tagged) => self.send(format!("@label={};{}", label, tagged)),
+            None => self.send(format!("@label={} {}", label, line)),
+        }
+        labels.pending.insert(label, tx);
+        Some(rx)
+    }
+
+    /// Says `text` to `target`. With echo-message the line is shown once the
+    /// server echoes it back; otherwise it is shown right away. Multi-line or
+    /// overlong text goes out as draft/multiline batches where supported, as
+    /// separate lines otherwise; the receiver answers for the first of them.
+    pub fn privmsg(&self, target: &str, text: &str) -> Option<oneshot::Receiver<LabeledResponse>> {
+        self.say(target, text, None)
+    }
+
+    /// Like `privmsg`, marked as a reply to the message with `msgid` where the server allows.
+    pub fn reply(&self, target: &str, msgid: &str, text: &str) -> Option<oneshot::Receiver<LabeledResponse>> {
+        self.say(target, text, Some(msgid))
+    }
+
+    /// Tells `target` whether we are typing; repeats and needless `done`s are dropped.
+    pub fn send_typing(&self, target: &str, state: TypingState) {
+        if !self.inner.read().client_tag_allowed("typing") { return; }
+        if self.typing.lock().should_send(target, state, Instant::now()) {
+            self.send(format!("@+typing={} TAGMSG {}", state.as_str(), target));
+        }
+    }
+
+    /// Reacts to the message with `msgid`; false when the server does not allow reactions.
+    pub fn react(&self, target: &str, msgid: &str, reaction: &str) -> bool {
+        let st = self.inner.read();
+        if !st.client_tag_allowed("draft/react") || !st.client_tag_allowed("draft/reply") { return false; }
+        self.send(format!("@+draft/react={};+draft/reply={} TAGMSG {}", proto::escape_tag(reaction), proto::escape_tag(msgid), target));
+        true
+    }
+
+    /// Asks the bouncer for its networks; each arrives as an `Event::BouncerNetwork`.
+    pub fn list_networks(&self) { self.send("BOUNCER LISTNETWORKS"); }
+
+    /// Networks the bouncer has told us about so far.
+    pub fn bouncer_networks(&self) -> Vec<BouncerNetwork> { self.bouncer.lock().iter().cloned().collect() }
+
+    /// Adds an upstream network (name, host, port, nickname...); the reply carries its netid.
+    pub fn add_network(&self, attrs: &BTreeMap<String, String>) -> Option<oneshot::Receiver<LabeledResponse>> {
+        self.send_labeled(&format!("BOUNCER ADDNETWORK {}", bouncer::format_attrs(attrs)))
+    }
+
+    /// Changes attributes of a network; empty values unset them.
+    pub fn change_network(&self, id: &str, attrs: &BTreeMap<String, String>) -> Option<oneshot::Receiver<LabeledResponse>> {
+        self.send_labeled(&format!("BOUNCER CHANGENETWORK {} {}", id, bouncer::format_attrs(attrs)))
+    }
+
+    pub fn remove_network(&self, id: &str) -> Option<oneshot::Receiver<LabeledResponse>> {
+        self.send_labeled(&format!("BOUNCER DELNETWORK {}", id))
+    }
+
+    pub fn set_completion_config(&self, cfg: CompletionConfig) { self.completer.lock().cfg = cfg; }
+
+    /// Tab completion of `line` at byte offset `cursor` in `buffer`; calling it
+    /// again with the returned line and cursor gives the next candidate.
+    pub fn complete(&self, buffer: &BufferId, line: &str, cursor: usize) -> Option<Completion> {
+        let st = self.inner.read();
+        self.completer.lock().complete(&st, buffer, line, cursor)
+    }
+
+    /// Nicks typing in `id` right now.
+    pub fn typing(&self, id: &BufferId) -> Vec<(String, TypingState)> { self.typing.lock().typing(id) }
+
+    fn say(&self, target: &str, text: &str, reply_to: Option<&str>) -> Option<oneshot::Receiver<LabeledResponse>> {
+        let (max_len, limits, tag) = {
+            let st = self.inner.read();
+            let tag = reply_to.filter(|_| st.client_tag_allowed("draft/reply"))
+                .map(|m| format!("@+draft/reply={} ", proto::escape_tag(m)))
+                .unwrap_or_default();
+            let max_bytes = st.cap_param("draft/multiline", "max-bytes").and_then(|v| v.parse().ok());
+            let max_lines = st.cap_param("draft/multiline", "max-lines").and_then(|v| v.parse().ok());
+            (st.max_text_len(target), max_bytes.map(|b: usize| (b, max_lines)), tag)
+        };
+        self.typing.lock().sent_message(target);
+        let rx = match limits {
+            Some((max_bytes, max_lines)) if text.contains('\n') || text.len() > max_len => {
+                let mut rx = None;
+                for parts in multiline::split(text, max_len, max_bytes, max_lines) {
+                    let reference = { let mut l = self.labels.lock(); l.next += 1; format!("ml{}", l.next) };
+                    let open = self.send_labeled(&format!("{}BATCH +{} draft/multiline {}", tag, reference, target));
+                    rx = rx.or(open);
+                    for p in parts {
+                        let concat = if p.concat { ";draft/multiline-concat" } else { "" };
+                        self.send(format!("@batch={}{} PRIVMSG {} :{}", reference, concat, target, p.text));
+                    }
+                    self.send(format!("BATCH -{}", reference));
+                }
+                rx
+            }
+            _ => {
+                let mut rx = None;
+                for line in text.lines().filter(|l| !l.is_empty()).flat_map(|l| multiline::chunks(l, max_len)) {
+                    let tag = if rx.is_none() { tag.as_str() } else { "" };
+                    let sent = self.send_labeled(&format!("{}PRIVMSG {} :{}", tag, target, line));
+                    rx = rx.or(sent);
+                }
+                rx
+            }
+        };
+        let (echo, nick) = { let st = self.inner.read(); (st.has_cap("echo-message"), st.nick.clone()) };
+        if !echo {
+            let ev = Event::PrivMsg{ from: nick, account: None, target: target.to_string(), text: text.to_string(), reply_to: reply_to.map(str::to_string), highlight: None };
+            self.record(&ev, SystemTime::now(), None);
+        }
+        rx
+    }
+
+    fn resolve_label(&self, label: Option<&str>, response: LabeledResponse) {
+        let Some(label) = label else { return };
+        if let Some(tx) = self.labels.lock().pending.remove(label) { let _ = tx.send(response); }
+    }
+
+    /// Lines the engine wants sent to the server (PONG replies, lag PINGs...).
+    pub fn take_outgoing(&self) -> Vec<String> { std::mem::take(&mut *self.outgoing.lock()) }
+
+    fn send(&self, line: impl Into<String>) { self.outgoing.lock().push(line.into()); }
+
+    /// Drives lag PINGs, dead-link detection and netsplit reporting; call about once a second.
+    pub fn tick(&self) -> Vec<TimedEvent> {
+        let now = Instant::now();
+        let time = SystemTime::now();
+        let mut events: Vec<Event> = self.netsplit.lock().settle(now).into_iter().map(|s| match s {
+            Settled::Split(g) => Event::Netsplit{ by_channel: g.by_channel(), nicks: g.nicks.into_keys().collect(), servers: g.servers },
+            Settled::Join(g) => Event::Netjoin{ by_channel: g.by_channel(), nicks: g.nicks.into_keys().collect(), servers: g.servers },
+        }).collect();
+        for ev in &events { self.record(ev, time, None); }
+        for (nick, target) in self.typing.lock().expire(now) {
+            events.push(Event::Typing{ nick, target, state: TypingState::Done });
+        }
+
+        let mut live = self.live.lock();
+        if now.duration_since(live.last_rx) >= live.cfg.timeout {
+            events.push(Event::PingTimeout);
+        } else if let Some((_, sent)) = &live.lag_token {
+            // still waiting: let the lag meter climb like HexChat's does
+            events.push(Event::LagUpdate{ lag_ms: now.duration_since(*sent).as_millis() as u64 });
+        } else if now.duration_since(live.last_ping) >= live.cfg.interval {
+            let stamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+            let token = format!("LAG{}", stamp);
+            self.send(format!("PING :{}", token));
+            live.lag_token = Some((token, now));
+            live.last_ping = now;
+        }
+        events.into_iter().map(|event| TimedEvent{ time, msgid: None, event }).collect()
+    }
+
+    /// Sends a CHATHISTORY request for `target`; false when the server has no history.
+    /// Replies arrive as a chathistory `Event::Batch` and are backfilled into scrollback.
+    pub fn fetch_history(&self, target: &str, req: HistoryRequest, limit: usize) -> bool {
+        let line = self.inner.read().history_command(target, req, limit);
+        line.map(|l| self.send(l)).is_some()
+    }
+
+    /// "Load older": fetches the page before the oldest line we have for `id`.
+    pub fn load_older(&self, id: &BufferId, limit: usize) -> bool {
+        let Some(target) = id.name() else { return false };
+        let req = self.scrollback.lock().oldest(id).map_or(HistoryRequest::Latest, |l| HistoryRequest::Before(l.time));
+        self.fetch_history(target, req, limit)
+    }
+
+    // what we missed in a buffer: everything after its newest line, or just the latest page
+    fn catch_up(&self, st: &ServerState, id: BufferId) {
+        let Some(target) = id.name() else { return };
+        let req = self.scrollback.lock().newest(&id).map_or(HistoryRequest::Latest, |l| HistoryRequest::After(l.time));
+        if let Some(line) = st.history_command(target, req, HISTORY_PAGE) { self.send(line); }
+    }
+
+    /// Handles one line from the server; `None` while it is held in an open BATCH.
+    pub fn on_message(&self, msg: Message) -> Option<TimedEvent> {
+        self.live.lock().last_rx = Instant::now();
+        let time = msg.server_time().unwrap_or_else(SystemTime::now);
+        let msgid = msg.tag("msgid").map(str::to_string);
+        let handled = self.batches.lock().handle(msg);
+        let (label, ev) = match handled {
+            Handled::Live(msg) if msg.command == "ACK" => {
+                self.resolve_label(msg.tag("label"), LabeledResponse::Ack);
+                return None;
+            }
+            Handled::Live(msg) => {
+                let label = msg.tag("label").map(str::to_string);
+                let event = self.process(msg, time);
+                self.record(&event, time, msgid.as_deref());
+                (label, TimedEvent{ time, msgid, event })
+            }
+            Handled::Held => return None,
+            Handled::Closed(batch) => (batch.tag("label").map(str::to_string), self.finish_batch(batch, time, false)),
+        };
+        self.resolve_label(label.as_deref(), LabeledResponse::Event(Box::new(ev.clone())));
+        Some(ev)
+    }
+
+    /// PRIVMSG/NOTICE from a chathistory batch, without the side effects live ones have.
+    fn history_event(&self, msg: Message) -> Event {
+        let from = msg.prefix.as_ref().map(|p| p.nick().to_string()).unwrap_or_default();
+        let account = msg.tag("account").map(str::to_string);
+        let target = msg.params.first().cloned().unwrap_or_default();
+        let text = msg.params.get(1).cloned().unwrap_or_default();
+        let highlight = self.highlight_in(&self.inner.read(), &from, account.as_deref(), &target, &text);
+        match msg.command.as_str() {
+            "PRIVMSG" => Event::PrivMsg{ from, account, target, text, reply_to: msg.tag("+draft/reply").map(str::to_string), highlight },
+            "NOTICE" => Event::Notice{ from, account, target, text, highlight },
+            _ => Event::Unknown(msg),
+        }
+    }
+
+    // `history`: inside a chathistory batch, so nothing may touch live state
+    fn finish_batch(&self, batch: Batch, time: SystemTime, history: bool) -> TimedEvent {
+        let time = batch.tag("time").and_then(proto::parse_server_time).unwrap_or(time);
+        let msgid = batch.tag("msgid").map(str::to_string);
+        let history = history || batch.kind == "chathistory";
+        if batch.kind == "draft/multiline" {
+            if let Some(msg) = multiline::join(&batch) {
+                let event = if history { self.history_event(msg) } else { self.process(msg, time) };
+                self.record(&event, time, msgid.as_deref());
+                return TimedEvent{ time, msgid, event };
+            }
+        }
+        let grouped = batch.kind == "netsplit" || batch.kind == "netjoin";
+        let mut events = Vec::new();
+        for item in batch.items {
+            let ev = match item {
+                BatchItem::Batch(child) => self.finish_batch(child, time, history),
+                BatchItem::Message(msg) => {
+                    let time = msg.server_time().unwrap_or(time);
+                    let msgid = msg.tag("msgid").map(str::to_string);
+                    let event = if history { self.history_event(msg) } else { self.process(msg, time) };
+                    if !grouped { self.record(&event, time, msgid.as_deref()); }
+                    TimedEvent{ time, msgid, event }
+                }
+            };
+            events.push(ev);
+        }
+        if !grouped {
+            return TimedEvent{ time, msgid, event: Event::Batch{ kind: batch.kind, params: batch.params, events } };
+        }
+        let servers = (batch.params.first().cloned().unwrap_or_default(), batch.params.get(1).cloned().unwrap_or_default());
+        let mut nicks: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for ev in events {
+            match ev.event {
+                Event::Quit{ nick, channels, .. } => nicks.entry(nick).or_default().extend(channels),
+                Event::Join{ nick, channel } => nicks.entry(nick).or_default().push(channel),
+                _ => {}
+            }
+        }
+        let g = netsplit::SplitGroup{ servers, nicks };
+        let event = if batch.kind == "netsplit" {
+            Event::Netsplit{ by_channel: g.by_channel(), nicks: g.nicks.into_keys().collect(), servers: g.servers }
+        } else {
+            Event::Netjoin{ by_channel: g.by_channel(), nicks: g.nicks.into_keys().collect(), servers: g.servers }
+        };
+        self.record(&event, time, None);
+        TimedEvent{ time, msgid, event }
+    }
+
+    /// Appends the formatted form of `ev` to the scrollback of every buffer it
+    /// belongs to and bumps those buffers' activity. Lines already in a buffer
+    /// (history we were sent twice) are skipped.
+    fn record(&self, ev: &Event, time: SystemTime, msgid: Option<&str>) {
+        let st = self.inner.read();
+        let msg_level = |from: &str, highlight: &Option<Highlight>| {
+            if from == st.nick { Activity::None }
+            else if highlight.is_some() { Activity::Highlight }
+            else { Activity::Message }
+        };
+        let chantypes = st.isupport("CHANTYPES").unwrap_or("#&");
+        let lines: Vec<(BufferId, String, Activity)> = match ev {
+            Event::Welcome(text) => vec![(BufferId::Server, text.clone(), Activity::Events)],
+            Event::Join{ nick, .. } if self.netsplit.lock().is_joining(nick) => Vec::new(),
+            Event::Join{ nick, channel } => vec![(BufferId::Channel(channel.clone()), format!("{} has joined {}", nick, channel), Activity::Events)],
+            Event::Part{ nick, channel } => vec![(BufferId::Channel(channel.clone()), format!("{} has left {}", nick, channel), Activity::Events)],
+            Event::Quit{ nick, reason, channels } => {
+                if self.netsplit.lock().is_split(nick) { return; }
+                channels.iter().map(|c| BufferId::Channel(c.clone()))
+                    .chain(st.find_query(nick).map(|q| BufferId::Query(q.clone())))
+                    .map(|buf| (buf, format!("{} has quit ({})", nick, reason), Activity::Events))
+                    .collect()
+            }
+            Event::Netsplit{ servers, by_channel, .. } => by_channel.iter()
+                .map(|(c, nicks)| (BufferId::Channel(c.clone()), format!("Netsplit {} <-> {} quits: {}", servers.0, servers.1, nicks.join(", ")), Activity::Events))
+                .collect(),
+            Event::Netjoin{ servers, by_channel, .. } => by_channel.iter()
+                .map(|(c, nicks)| (BufferId::Channel(c.clone()), format!("Netsplit {} <-> {} over, joins: {}", servers.0, servers.1, nicks.join(", ")), Activity::Events))
+                .collect(),
+            Event::PrivMsg{ from, target, text, highlight, .. } => {
+                let buf = st.buffer_for(from, target);
+                self.urls.lock().grab(&buf, from, time, text, chantypes);
+                let line = match text.strip_prefix("\x01ACTION ") {
+                    Some(action) => format!("* {} {}", from, action.trim_end_matches('\x01')),
+                    None => format!("<{}> {}", from, text),
+                };
+                vec![(buf, line, msg_level(from, highlight))]
+            }
+            Event::Notice{ from, target, text, highlight, .. } => {
+                let buf = match st.buffer_for(from, target) {
+                    BufferId::Query(q) if st.find_query(&q).is_none() => BufferId::Server,
+                    buf => buf,
+                };
+                self.urls.lock().grab(&buf, from, time, text, chantypes);
+                vec![(buf, format!("-{}- {}", from, text), msg_level(from, highlight))]
+            }
+            Event::Mode{ target, by, changes } if st.is_channel(target) => {
+                let desc: Vec<String> = changes.iter()
+                    .map(|c| format!("{}{}{}", if c.set { '+' } else { '-' }, c.mode, c.arg.as_ref().map(|a| format!(" {}", a)).unwrap_or_default()))
+                    .collect();
+                vec![(BufferId::Channel(target.clone()), format!("{} sets mode {}", by, desc.join(", ")), Activity::Events)]
+            }
+            Event::Topic{ channel, text } => vec![(BufferId::Channel(channel.clone()), format!("Topic for {} is: {}", channel, text), Activity::Events)],
+            Event::Nick{ old, new } => st.channels.values()
+                .filter(|c| c.users.contains(new))
+                .map(|c| BufferId::Channel(c.name.clone()))
+                .chain(st.find_query(new).map(|q| BufferId::Query(q.clone())))
+                .map(|buf| (buf, format!("{} is now known as {}", old, new), Activity::Events))
+                .collect(),
+            Event::Invite{ by, nick, channel } if nick.eq_ignore_ascii_case(&st.nick) => {
+                vec![(BufferId::Server, format!("You have been invited to {} by {}", channel, by), Activity::Message)]
+            }
+            Event::Invite{ by, nick, channel } if st.channels.contains_key(&ChannelId(channel.clone())) => {
+                vec![(BufferId::Channel(channel.clone()), format!("{} has invited {} to {}", by, nick, channel), Activity::Events)]
+            }
+            Event::Unknown(m) if m.command == "321" || m.command == "322" || m.command == "301" => Vec::new(),
+            Event::AwayReply{ nick, message } => {
+                let buf = st.find_query(nick).map(|q| BufferId::Query(q.clone())).unwrap_or(BufferId::Server);
+                vec![(buf, format!("{} is away ({})", nick, message), Activity::Events)]
+            }
+            Event::SelfAway{ message } => {
+                let line = match message {
+                    Some(m) => format!("You are now marked as away ({})", m),
+                    None => "You are no longer marked as away".to_string(),
+                };
+                vec![(BufferId::Server, line, Activity::Events)]
+            }
+            Event::Unknown(m) if m.command.chars().all(|c| c.is_ascii_digit()) && !m.command.is_empty() => {
+                vec![(BufferId::Server, m.params.iter().skip(1).cloned().collect::<Vec<_>>().join(" "), Activity::Events)]
+            }
+            _ => Vec::new(),
+        };
+        drop(st);
+        let mut sb = self.scrollback.lock();
+        let mut act = self.activity.lock();
+        for (buf, text, level) in lines {
+            // backfilled lines we already read elsewhere are not new
+            let seen = act.read_marker(&buf).is_some_and(|m| time <= m);
+            if sb.insert(buf.clone(), Line{ time, text, msgid: msgid.map(str::to_string) }) && !seen { act.note(&buf, level, time); }
+        }
+    }
+
+    fn process(&self, msg: Message, time: SystemTime) -> Event {
+        let mut st = self.inner.write();
+        // account-tag: any message from a user says who they are logged in as
+        if let (Some(account), Some(p)) = (msg.tag("account"), &msg.prefix) {
+            if let Some(u) = st.users.get_mut(p.nick()) { u.account = Some(account.to_string()); }
+        }
         match msg.command.as_str() {
+            "PING" => {
+                let token = msg.params.last().cloned().unwrap_or_default();
+                self.send(format!("PONG :{}", token));
+                Event::Ping(token)
+            }
+            "PONG" => {
+                let token = msg.params.last().cloned().unwrap_or_default();
+                let mut live = self.live.lock();
+                match live.lag_token.take() {
+                    Some((expected, sent)) if expected == token => {
+                        let lag_ms = sent.elapsed().as_millis() as u64;
+                        st.lag_ms = Some(lag_ms);
+                        Event::LagUpdate{ lag_ms }
+                    }
+                    pending => { live.lag_token = pending; Event::Unknown(msg) }
+                }
+            }
             "001" => Event::Welcome(msg.params.get(1).cloned().unwrap_or_default()),
             "JOIN" => {
                 let who = msg.prefix.as_ref().map(|p| p.raw.split('!').next().unwrap_or(&p.raw).to_string()).unwrap_or_default();
-                let chan = msg.params.last().cloned().unwrap_or_default();
+                // extended-join: JOIN <channel> <account> :<realname>
+                let chan = msg.params.first().cloned().unwrap_or_default();
                 let id = ChannelId(chan.clone());
                 st.channels.entry(id.clone()).or_insert(Channel{
                     name: chan.clone(),
-                    users: HashSet::new(),
+                    ..Default::default()
                 }).users.insert(who.clone());
+                let u = st.users.entry(who.clone()).or_insert_with(|| User{ nick: who.clone(), ..Default::default() });
+                if let Some(p) = &msg.prefix {
+                    u.user = p.user().map(str::to_string);
+                    u.host = p.host().map(str::to_string);
+                }
+                if let [_, account, realname] = &msg.params[..] {
+                    u.account = Some(account.clone()).filter(|a| a != "*");
+                    u.realname = Some(realname.clone());
+                }
+                // batched netjoins are grouped by their BATCH already
+                if msg.tag("batch").is_none() { self.netsplit.lock().join(&who, &chan, Instant::now()); }
+                if who == st.nick { self.catch_up(&st, BufferId::Channel(chan.clone())); }
                 Event::Join{ nick: who, channel: chan }
             }
+            // RPL_NAMREPLY: <me> <type> <channel> :[prefixes]nick[!user@host] ...
+            "353" => {
+                let chan = msg.params.get(2).cloned().unwrap_or_default();
+                let prefix = st.isupport("PREFIX").unwrap_or("(ov)@+").to_string();
+                let symbols = prefix.split_once(')').map_or("", |(_, s)| s).to_string();
+                // NAMES for a channel we are not in says nothing about our members
+                if !st.channels.contains_key(&ChannelId(chan.clone())) { return Event::Unknown(msg); }
+                let names = msg.params.get(3).map(String::as_str).unwrap_or("");
+                for name in names.split_whitespace() {
+                    // multi-prefix may stack several symbols
+                    let full = name.trim_start_matches(|c| symbols.contains(c));
+                    let (nick, userhost) = full.split_once('!').map_or((full, None), |(n, uh)| (n, Some(uh)));
+                    if nick.is_empty() { continue; }
+                    if let Some(c) = st.channels.get_mut(&ChannelId(chan.clone())) { c.users.insert(nick.to_string()); }
+                    let u = st.users.entry(nick.to_string()).or_insert_with(|| User{ nick: nick.to_string(), ..Default::default() });
+                    // userhost-in-names
+                    if let Some((user, host)) = userhost.and_then(|uh| uh.split_once('@')) {
+                        u.user = Some(user.to_string());
+                        u.host = Some(host.to_string());
+                    }
+                }
+                Event::Unknown(msg)
+            }
             "PART" => {
                 let who = msg.prefix.as_ref().map(|p| p.raw.split('!').next().unwrap_or(&p.raw).to_string()).unwrap_or_default();
                 let chan = msg.params.first().cloned().unwrap_or_default();
                 let id = ChannelId(chan.clone());
-                if let Some(c) = st.channels.get_mut(&id) { c.users.remove(&who); }
+                if let Some(c) = st.channels.get_mut(&id) {
+                    c.users.remove(&who);
+                    c.last_spoke.remove(&who);
+                }
+                if who == st.nick { st.channels.remove(&id); }
+                if !st.shares_channel(&who) { st.users.remove(&who); }
                 Event::Part{ nick: who, channel: chan }
             }
+            "QUIT" => {
+                let who = msg.prefix.as_ref().map(|p| p.raw.split('!').next().unwrap_or(&p.raw).to_string()).unwrap_or_default();
+                let reason = msg.params.first().cloned().unwrap_or_default();
+                let mut channels: Vec<String> = st.channels.values_mut()
+                    .filter_map(|c| { c.last_spoke.remove(&who); c.users.remove(&who).then(|| c.name.clone()) })
+                    .collect();
+                channels.sort();
+                st.users.remove(&who);
+                if msg.tag("batch").is_none() { self.netsplit.lock().quit(&who, &reason, &channels, Instant::now()); }
+                Event::Quit{ nick: who, reason, channels }
+            }
             "PRIVMSG" => {
                 let who = msg.prefix.as_ref().map(|p| p.raw.split('!').next().unwrap_or(&p.raw).to_string()).unwrap_or_default();
                 let target = msg.params.get(0).cloned().unwrap_or_default();
                 let text = msg.params.get(1).cloned().unwrap_or_default();
-                Event::PrivMsg{ from: who, target, text }
+                if let BufferId::Query(q) = st.buffer_for(&who, &target) {
+                    if st.find_query(&q).is_none() { st.queries.push(q); }
+                }
+                let account = msg.tag("account").or_else(|| st.account_of(&who)).map(str::to_string);
+                let reply_to = msg.tag("+draft/reply").map(str::to_string);
+                let buf = st.buffer_for(&who, &target);
+                if let BufferId::Channel(name) = &buf {
+                    if let Some(c) = st.channels.get_mut(&ChannelId(name.clone())) { c.last_spoke.insert(who.clone(), time); }
+                }
+                // their message is what they were typing
+                self.typing.lock().clear(&buf, &who);
+                let highlight = self.highlight_in(&st, &who, account.as_deref(), &target, &text);
+                Event::PrivMsg{ from: who, account, target, text, reply_to, highlight }
+            }
+            "BOUNCER" if msg.params.first().map(String::as_str) == Some("NETWORK") => {
+                let id = msg.params.get(1).cloned().unwrap_or_default();
+                let network = self.bouncer.lock().update(&id, msg.params.get(2).map(String::as_str).unwrap_or(""));
+                Event::BouncerNetwork{ id, network }
+            }
+            "MARKREAD" => {
+                // MARKREAD <target> timestamp=<time>|*
+                let target = msg.params.first().cloned().unwrap_or_default();
+                let time = msg.params.get(1).and_then(|t| t.strip_prefix("timestamp=")).and_then(proto::parse_server_time);
+                let id = if st.is_channel(&target) { BufferId::Channel(target.clone()) } else { st.buffer_for(&st.nick, &target) };
+                if let Some(t) = time {
+                    let mut act = self.activity.lock();
+                    // an older marker than ours changes nothing
+                    if act.set_read_marker(&id, t) { act.recount(&id, t); }
+                }
+                Event::ReadMarker{ target, time }
+            }
+            "TAGMSG" => {
+                let who = msg.prefix.as_ref().map(|p| p.nick().to_string()).unwrap_or_default();
+                let target = msg.params.first().cloned().unwrap_or_default();
+                if who == st.nick { return Event::Unknown(msg); }
+                if let Some(state) = msg.tag("+typing").and_then(TypingState::parse) {
+                    self.typing.lock().update(st.buffer_for(&who, &target), &who, &target, state, Instant::now());
+                    return Event::Typing{ nick: who, target, state };
+                }
+                match (msg.tag("+draft/react"), msg.tag("+draft/reply")) {
+                    (Some(reaction), Some(msgid)) => Event::Reaction{ nick: who, target, msgid: msgid.to_string(), reaction: reaction.to_string() },
+                    _ => Event::Unknown(msg),
+                }
             }
             "NOTICE" => {
                 let who = msg.prefix.as_ref().map(|p| p.raw.split('!').next().unwrap_or(&p.raw).to_string()).unwrap_or_default();
                 let target = msg.params.get(0).cloned().unwrap_or_default();
                 let text = msg.params.get(1).cloned().unwrap_or_default();
-                Event::Notice{ from: who, target, text }
+                let account = msg.tag("account").or_else(|| st.account_of(&who)).map(str::to_string);
+                let highlight = self.highlight_in(&st, &who, account.as_deref(), &target, &text);
+                Event::Notice{ from: who, account, target, text, highlight }
+            }
+            "NICK" => {
+                let old = msg.prefix.as_ref().map(|p| p.raw.split('!').next().unwrap_or(&p.raw).to_string()).unwrap_or_default();
+                let new = msg.params.first().cloned().unwrap_or_default();
+                for c in st.channels.values_mut() {
+                    if c.users.remove(&old) { c.users.insert(new.clone()); }
+                    if let Some(t) = c.last_spoke.remove(&old) { c.last_spoke.insert(new.clone(), t); }
+                }
+                if st.nick == old { st.nick = new.clone(); }
+                if let Some(mut u) = st.users.remove(&old) {
+                    u.nick = new.clone();
+                    st.users.insert(new.clone(), u);
+                }
+                if let Some(q) = st.queries.iter_mut().find(|q| q.eq_ignore_ascii_case(&old)) {
+                    *q = new.clone();
+                    let (from, to) = (BufferId::Query(old.clone()), BufferId::Query(new.clone()));
+                    self.scrollback.lock().rename(&from, to.clone());
+                    self.activity.lock().rename(&from, to);
+                }
+                Event::Nick{ old, new }
+            }
+            "ACCOUNT" => {
+                let nick = msg.prefix.as_ref().map(|p| p.nick().to_string()).unwrap_or_default();
+                let account = msg.params.first().cloned().filter(|a| a != "*");
+                if let Some(u) = st.users.get_mut(&nick) { u.account = account.clone(); }
+                Event::AccountChange{ nick, account }
+            }
+            "CHGHOST" => {
+                let nick = msg.prefix.as_ref().map(|p| p.nick().to_string()).unwrap_or_default();
+                let user = msg.params.first().cloned().unwrap_or_default();
+                let host = msg.params.get(1).cloned().unwrap_or_default();
+                if let Some(u) = st.users.get_mut(&nick) {
+                    u.user = Some(user.clone());
+                    u.host = Some(host.clone());
+                }
+                Event::HostChange{ nick, user, host }
+            }
+            "SETNAME" => {
+                let nick = msg.prefix.as_ref().map(|p| p.nick().to_string()).unwrap_or_default();
+                let realname = msg.params.first().cloned().unwrap_or_default();
+                if let Some(u) = st.users.get_mut(&nick) { u.realname = Some(realname.clone()); }
+                Event::RealnameChange{ nick, realname }
+            }
+            "INVITE" => {
+                let by = msg.prefix.as_ref().map(|p| p.nick().to_string()).unwrap_or_default();
+                let nick = msg.params.first().cloned().unwrap_or_default();
+                let channel = msg.params.get(1).cloned().unwrap_or_default();
+                Event::Invite{ by, nick, channel }
+            }
+            "730" | "731" => {
+                let targets = msg.params.get(1).map(|t| t.split(',').map(|m| m.split('!').next().unwrap_or(m).to_string()).collect()).unwrap_or_default();
+                if msg.command == "730" { Event::MonitorOnline(targets) } else { Event::MonitorOffline(targets) }
+            }
+            "CAP" => {
+                // caps changing after registration (cap-notify, or a REQ of our own)
+                let list = msg.params.last().cloned().unwrap_or_default();
+                match msg.params.get(1).map(String::as_str) {
+                    Some("ACK") => for c in list.split_whitespace() {
+                        match c.strip_prefix('-') {
+                            Some(off) => { st.caps.remove(off); }
+                            None => { st.caps.entry(c.to_string()).or_default(); }
+                        }
+                    },
+                    Some("DEL") => for c in list.split_whitespace() { st.caps.remove(c); },
+                    _ => {}
+                }
+                Event::Unknown(msg)
+            }
+            "376" | "422" => {
+                // end of MOTD: registration is over, catch up on open queries
+                for q in &st.queries { self.catch_up(&st, BufferId::Query(q.clone())); }
+                Event::Unknown(msg)
+            }
+            "005" => {
+                // params: <nick> <token>... :are supported by this server
+                for tok in msg.params.iter().skip(1).take(msg.params.len().saturating_sub(2)) {
+                    if let Some(key) = tok.strip_prefix('-') { st.isupport.remove(key); continue; }
+                    let (k, v) = tok.split_once('=').unwrap_or((tok, ""));
+                    st.isupport.insert(k.to_string(), v.to_string());
+                }
+                Event::Unknown(msg)
+            }
+            "MODE" => {
+                let by = msg.prefix.as_ref().map(|p| p.nick().to_string()).unwrap_or_default();
+                let target = msg.params.first().cloned().unwrap_or_default();
+                let chanmodes = st.isupport("CHANMODES").unwrap_or("beI,k,l,imnpst").to_string();
+                let prefix = st.isupport("PREFIX").unwrap_or("(ov)@+").to_string();
+                let args = msg.params.get(2..).unwrap_or_default();
+                let changes = modes::parse_modes(msg.params.get(1).map(String::as_str).unwrap_or(""), args, &chanmodes, modes::prefix_modes(&prefix));
+                if let Some(c) = st.channels.get_mut(&ChannelId(target.clone())) {
+                    let set_at = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+                    for ch in &changes {
+                        let (Some(mode), Some(mask)) = (ListMode::from_char(ch.mode), &ch.arg) else { continue };
+                        // only lists we have fetched are kept current
+                        let Some(list) = c.mask_lists.get_mut(&mode) else { continue };
+                        list.retain(|e| !e.mask.eq_ignore_ascii_case(mask));
+                        if ch.set { list.push(MaskEntry{ mask: mask.clone(), setter: Some(by.clone()), set_at: Some(set_at) }); }
+                    }
+                }
+                Event::Mode{ target, by, changes }
+            }
+            "367" | "368" | "348" | "349" | "346" | "347" => {
+                // <me> <channel> <mask> [<setter> <time>], then <me> <channel> :End of list
+                let (mode, end) = ListMode::from_numeric(&msg.command).unwrap_or((ListMode::Ban, true));
+                let channel = msg.params.get(1).cloned().unwrap_or_default();
+                let Some(c) = st.channels.get_mut(&ChannelId(channel.clone())) else { return Event::Unknown(msg) };
+                if end {
+                    // no entries before the end: the list is empty now, whatever we had cached
+                    if !c.loading.remove(&mode) { c.mask_lists.insert(mode, Vec::new()); }
+                    let entries = c.mask_lists[&mode].clone();
+                    return Event::MaskList{ channel, mode, entries };
+                }
+                if c.loading.insert(mode) { c.mask_lists.insert(mode, Vec::new()); }
+                c.mask_lists.entry(mode).or_default().push(MaskEntry{
+                    mask: msg.params.get(2).cloned().unwrap_or_default(),
+                    setter: msg.params.get(3).cloned(),
+                    set_at: msg.params.get(4).and_then(|t| t.parse().ok()),
+                });
+                Event::Unknown(msg)
+            }
+            "305" => {
+                st.away = None;
+                self.away.lock().sent_away(None, false);
+                Event::SelfAway{ message: None }
+            }
+            "306" => {
+                st.away = Some(self.away.lock().confirmed().unwrap_or_default());
+                Event::SelfAway{ message: st.away.clone() }
+            }
+            "AWAY" => {
+                let nick = msg.prefix.as_ref().map(|p| p.nick().to_string()).unwrap_or_default();
+                let message = msg.params.first().cloned().filter(|m| !m.is_empty());
+                if let Some(u) = st.users.get_mut(&nick) {
+                    u.away = message.is_some();
+                    u.away_message = message.clone();
+                }
+                if message.is_none() { self.away.lock().forget(&nick); }
+                Event::Away{ nick, message }
+            }
+            "301" => {
+                // <me> <nick> :<message>; also part of WHOIS replies
+                self.info.lock().handle(&msg);
+                let nick = msg.params.get(1).cloned().unwrap_or_default();
+                let message = msg.params.get(2).cloned().unwrap_or_default();
+                if let Some(u) = st.users.get_mut(&nick) {
+                    u.away = true;
+                    u.away_message = Some(message.clone());
+                }
+                if self.away.lock().should_show(&nick, &message) { Event::AwayReply{ nick, message } } else { Event::Unknown(msg) }
+            }
+            "322" => {
+                // <me> <channel> <users> :<topic>
+                let entry = ListEntry {
+                    channel: msg.params.get(1).cloned().unwrap_or_default(),
+                    users: msg.params.get(2).and_then(|n| n.parse().ok()).unwrap_or(0),
+                    topic: msg.params.get(3).cloned().unwrap_or_default(),
+                };
+                let mut list = self.chanlist.lock();
+                match list.add(entry) {
+                    Some(entry) => Event::ListEntry{ entry, count: list.count(), received: list.received() },
+                    None if list.in_progress() => Event::ListProgress{ count: list.count(), received: list.received() },
+                    None => Event::Unknown(msg),
+                }
+            }
+            "323" => {
+                let mut list = self.chanlist.lock();
+                list.finish();
+                Event::ListEnd{ count: list.count(), received: list.received() }
             }
             "332" => {
                 let chan = msg.params.get(1).cloned().unwrap_or_default();
                 let text = msg.params.get(2).cloned().unwrap_or_default();
                 Event::Topic{ channel: chan, text }
             }
-            _ => Event::Unknown(msg),
+            _ => match self.info.lock().handle(&msg) {
+                Some(Completed::Whois(r)) => { st.apply_whois(&r); Event::Whois(r) }
+                Some(Completed::WhoWas(r)) => Event::WhoWas(r),
+                Some(Completed::Who(r)) => {
+                    for reply in &r.replies { st.apply_who(reply); }
+                    Event::Who(r)
+                }
+                None => Event::Unknown(msg),
+            },
         }
     }
 }
+
@@ -0,0 +1,133 @@
+// Channel mode parsing, the ban/exception/invite-exception list caches and
+// the ban mask builder from HexChat's outbound.c create_mask().
+use serde::{Deserialize, Serialize};
+use std::net::Ipv4Addr;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ModeChange {
+    pub set: bool,
+    pub mode: char,
+    pub arg: Option<String>,
+}
+
+/// Splits `+o-b nick mask` into single changes. `chanmodes` is the CHANMODES
+/// token (list,always-arg,set-arg,no-arg) and `prefix_modes` the modes from PREFIX.
+pub fn parse_modes(modes: &str, args: &[String], chanmodes: &str, prefix_modes: &str) -> Vec<ModeChange> {
+    let groups: Vec<&str> = chanmodes.split(',').collect();
+    let group = |i: usize| groups.get(i).copied().unwrap_or("");
+    let mut args = args.iter();
+    let mut set = true;
+    let mut out = Vec::new();
+    for c in modes.chars() {
+        match c {
+            '+' => set = true,
+            '-' => set = false,
+            c => {
+                let takes_arg = prefix_modes.contains(c) || group(0).contains(c) || group(1).contains(c)
+                    || (set && group(2).contains(c));
+                let arg = if takes_arg { args.next().cloned() } else { None };
+                out.push(ModeChange { set, mode: c, arg });
+            }
+        }
+    }
+    out
+}
+
+/// Modes from a PREFIX token like `(ov)@+`.
+pub fn prefix_modes(prefix: &str) -> &str {
+    prefix.strip_prefix('(').and_then(|p| p.split(')').next()).unwrap_or("")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ListMode {
+    Ban,
+    Exception,
+    InviteException,
+}
+
+impl ListMode {
+    pub fn mode_char(self) -> char {
+        match self {
+            ListMode::Ban => 'b',
+            ListMode::Exception => 'e',
+            ListMode::InviteException => 'I',
+        }
+    }
+
+    pub fn from_char(c: char) -> Option<Self> {
+        match c {
+            'b' => Some(ListMode::Ban),
+            'e' => Some(ListMode::Exception),
+            'I' => Some(ListMode::InviteException),
+            _ => None,
+        }
+    }
+
+    /// (entry, end-of-list) numerics
+    pub fn from_numeric(cmd: &str) -> Option<(Self, bool)> {
+        match cmd {
+            "367" => Some((ListMode::Ban, false)),
+            "368" => Some((ListMode::Ban, true)),
+            "348" => Some((ListMode::Exception, false)),
+            "349" => Some((ListMode::Exception, true)),
+            "346" => Some((ListMode::InviteException, false)),
+            "347" => Some((ListMode::InviteException, true)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MaskEntry {
+    pub mask: String,
+    pub setter: Option<String>,
+    /// Unix time the entry was set, when the server says.
+    pub set_at: Option<u64>,
+}
+
+/// HexChat's irc_ban_type values.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BanType {
+    /// `*!*@*.domain` (0)
+    #[default]
+    Domain,
+    /// `*!*@full.host` (1)
+    Host,
+    /// `*!*user@*.domain` (2)
+    UserDomain,
+    /// `*!*user@full.host` (3)
+    UserHost,
+}
+
+/// Builds a ban mask for a user's ident and host the way HexChat's create_mask() does.
+pub fn ban_mask(user: &str, host: &str, ty: BanType) -> String {
+    // prefixed idents (~user, +user...) get the sign replaced by *, others get a * in front
+    let user = match user.chars().next() {
+        Some('~' | '+' | '=' | '^' | '-') => format!("*{}", &user[1..]),
+        _ => format!("*{}", user),
+    };
+    if host.parse::<Ipv4Addr>().is_ok() {
+        let net = host.rsplit_once('.').map(|(n, _)| n).unwrap_or(host);
+        return match ty {
+            BanType::Domain => format!("*!*@{}.*", net),
+            BanType::Host => format!("*!*@{}", host),
+            BanType::UserDomain => format!("*!{}@{}.*", user, net),
+            BanType::UserHost => format!("*!{}@{}", user, host),
+        };
+    }
+    let domain = host.find('.').map(|i| &host[i..]).unwrap_or(host);
+    match ty {
+        BanType::Domain => format!("*!*@*{}", domain),
+        BanType::Host => format!("*!*@{}", host),
+        BanType::UserDomain => format!("*!{}@*{}", user, domain),
+        BanType::UserHost => format!("*!{}@{}", user, host),
+    }
+}
+
+/// MODE lines applying `sign mode` to every arg, `per_line` at a time (ISUPPORT MODES).
+pub fn mode_lines(channel: &str, set: bool, mode: char, args: &[String], per_line: usize) -> Vec<String> {
+    args.chunks(per_line.max(1)).map(|chunk| {
+        let modes: String = std::iter::repeat_n(mode, chunk.len()).collect();
+        format!("MODE {} {}{} {}", channel, if set { '+' } else { '-' }, modes, chunk.join(" "))
+    }).collect()
+}
@@ -0,0 +1,72 @@
+// draft/multiline: pastes go out as BATCHes within the server's max-bytes and
+// max-lines, and received batches are joined back into one message.
+use crate::batch::{Batch, BatchItem};
+use proto::Message;
+
+/// One PRIVMSG of a multiline batch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Part {
+    pub text: String,
+    /// Continues the previous part without a line break (draft/multiline-concat).
+    pub concat: bool,
+}
+
+/// Cuts `line` into pieces of at most `max_len` bytes, on char boundaries.
+pub fn chunks(line: &str, max_len: usize) -> Vec<&str> {
+    let mut out = Vec::new();
+    let mut rest = line;
+    while rest.len() > max_len {
+        let mut at = max_len;
+        while !rest.is_char_boundary(at) { at -= 1; }
+        // a char wider than max_len still has to go somewhere
+        if at == 0 { at = rest.chars().next().map_or(rest.len(), char::len_utf8); }
+        out.push(&rest[..at]);
+        rest = &rest[at..];
+    }
+    out.push(rest);
+    out
+}
+
+/// Splits `text` into batches of parts: lines longer than `max_len` continue
+/// with concat parts, and each batch stays within `max_bytes` of content
+/// (line breaks included) and `max_lines` messages.
+pub fn split(text: &str, max_len: usize, max_bytes: usize, max_lines: Option<usize>) -> Vec<Vec<Part>> {
+    let max_lines = max_lines.unwrap_or(usize::MAX).max(1);
+    let mut batches: Vec<Vec<Part>> = Vec::new();
+    let mut cur: Vec<Part> = Vec::new();
+    let mut bytes = 0;
+    for line in text.lines() {
+        for (i, piece) in chunks(line, max_len).into_iter().enumerate() {
+            let cost = piece.len() + usize::from(i == 0 && !cur.is_empty());
+            if !cur.is_empty() && (bytes + cost > max_bytes || cur.len() >= max_lines) {
+                batches.push(std::mem::take(&mut cur));
+                bytes = 0;
+            }
+            // a batch boundary breaks the line anyway, so nothing to concat onto
+            let concat = i > 0 && !cur.is_empty();
+            bytes += piece.len() + usize::from(!concat && !cur.is_empty());
+            cur.push(Part { text: piece.to_string(), concat });
+        }
+    }
+    if !cur.is_empty() { batches.push(cur); }
+    batches
+}
+
+/// Joins a received multiline batch into one PRIVMSG/NOTICE with embedded newlines.
+pub(crate) fn join(batch: &Batch) -> Option<Message> {
+    let mut lines = batch.items.iter().filter_map(|i| match i {
+        BatchItem::Message(m) if m.command == "PRIVMSG" || m.command == "NOTICE" => Some(m),
+        _ => None,
+    });
+    let first = lines.next()?;
+    let mut text = first.params.get(1).cloned().unwrap_or_default();
+    for m in lines {
+        if m.tag("draft/multiline-concat").is_none() { text.push('\n'); }
+        text.push_str(m.params.get(1).map(String::as_str).unwrap_or(""));
+    }
+    let mut tags = first.tags.clone();
+    tags.remove("batch");
+    tags.remove("draft/multiline-concat");
+    let target = batch.params.first().cloned().or_else(|| first.params.first().cloned()).unwrap_or_default();
+    Some(Message { tags, prefix: first.prefix.clone(), command: first.command.clone(), params: vec![target, text] })
+}
@@ -0,0 +1,125 @@
+// Netsplit/netjoin aggregation. Split QUITs ("server1 server2") are grouped
+// per server pair and reported once the burst goes quiet; rejoins of split
+// nicks are collapsed the same way.
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+/// Quiet period after the last QUIT/JOIN of a burst before it is reported.
+const SETTLE: Duration = Duration::from_secs(2);
+/// How long split nicks are remembered while waiting for them to come back.
+const FORGET: Duration = Duration::from_secs(30 * 60);
+
+/// The two servers named in a netsplit QUIT reason, if it looks like one.
+pub fn split_servers(reason: &str) -> Option<(String, String)> {
+    let (a, b) = reason.split_once(' ')?;
+    let host = |h: &str| {
+        h.contains('.') && !h.starts_with('.') && !h.ends_with('.') && !h.contains("..")
+            && h.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '*')
+    };
+    if host(a) && host(b) && a != b { Some((a.to_string(), b.to_string())) } else { None }
+}
+
+#[derive(Debug, Clone)]
+pub struct SplitGroup {
+    pub servers: (String, String),
+    /// nick -> channels they were in
+    pub nicks: BTreeMap<String, Vec<String>>,
+}
+
+impl SplitGroup {
+    pub fn by_channel(&self) -> BTreeMap<String, Vec<String>> {
+        let mut out: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for (nick, chans) in &self.nicks {
+            for c in chans { out.entry(c.clone()).or_default().push(nick.clone()); }
+        }
+        out
+    }
+}
+
+#[derive(Debug)]
+struct Pending {
+    group: SplitGroup,
+    last: Instant,
+}
+
+#[derive(Debug, Default)]
+pub struct NetsplitTracker {
+    /// splits still collecting QUITs
+    splitting: Vec<Pending>,
+    /// reported splits whose nicks may still rejoin
+    split: Vec<Pending>,
+    /// rejoins being collected
+    joining: Vec<Pending>,
+}
+
+pub enum Settled {
+    Split(SplitGroup),
+    Join(SplitGroup),
+}
+
+impl NetsplitTracker {
+    /// Records a QUIT; returns true when it was absorbed into a netsplit.
+    pub fn quit(&mut self, nick: &str, reason: &str, channels: &[String], now: Instant) -> bool {
+        let Some(servers) = split_servers(reason) else { return false };
+        let idx = match self.splitting.iter().position(|p| p.group.servers == servers) {
+            Some(i) => i,
+            None => {
+                self.splitting.push(Pending { group: SplitGroup { servers, nicks: BTreeMap::new() }, last: now });
+                self.splitting.len() - 1
+            }
+        };
+        let p = &mut self.splitting[idx];
+        p.group.nicks.insert(nick.to_string(), channels.to_vec());
+        p.last = now;
+        true
+    }
+
+    /// Records a JOIN; returns true when it is a split nick coming back.
+    pub fn join(&mut self, nick: &str, channel: &str, now: Instant) -> bool {
+        let Some(si) = self.split.iter().position(|p| p.group.nicks.contains_key(nick)) else { return false };
+        let servers = self.split[si].group.servers.clone();
+        let idx = match self.joining.iter().position(|p| p.group.servers == servers) {
+            Some(i) => i,
+            None => {
+                self.joining.push(Pending { group: SplitGroup { servers, nicks: BTreeMap::new() }, last: now });
+                self.joining.len() - 1
+            }
+        };
+        let p = &mut self.joining[idx];
+        p.group.nicks.entry(nick.to_string()).or_default().push(channel.to_string());
+        p.last = now;
+        true
+    }
+
+    pub fn is_split(&self, nick: &str) -> bool {
+        self.splitting.iter().chain(&self.split).any(|p| p.group.nicks.contains_key(nick))
+    }
+
+    pub fn is_joining(&self, nick: &str) -> bool {
+        self.joining.iter().any(|p| p.group.nicks.contains_key(nick))
+    }
+
+    /// Reports bursts that have gone quiet.
+    pub fn settle(&mut self, now: Instant) -> Vec<Settled> {
+        let mut out = Vec::new();
+        let (done, open): (Vec<_>, Vec<_>) = std::mem::take(&mut self.splitting).into_iter()
+            .partition(|p| now.duration_since(p.last) >= SETTLE);
+        self.splitting = open;
+        for p in done {
+            out.push(Settled::Split(p.group.clone()));
+            self.split.push(p);
+        }
+        let (done, open): (Vec<_>, Vec<_>) = std::mem::take(&mut self.joining).into_iter()
+            .partition(|p| now.duration_since(p.last) >= SETTLE);
+        self.joining = open;
+        for p in done {
+            // once back, a nick is no longer waiting on the split
+            for s in self.split.iter_mut() {
+                s.group.nicks.retain(|n, _| !p.group.nicks.contains_key(n));
+            }
+            out.push(Settled::Join(p.group));
+        }
+        self.split.retain(|p| !p.group.nicks.is_empty() && now.duration_since(p.last) < FORGET);
+        out
+    }
+}
@@ -0,0 +1,188 @@
+// Per-buffer scrollback rings, modeled on HexChat's text.c scrollback_save/
+// scrollback_load. Lines pushed out of a full ring can be spilled to
+// <spill_dir>/<network>/<buffer>.txt using HexChat's "T <stamp> <text>" format.
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub enum BufferId {
+    Server,
+    Channel(String),
+    Query(String),
+}
+
+impl BufferId {
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            BufferId::Server => None,
+            BufferId::Channel(n) | BufferId::Query(n) => Some(n),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Line {
+    pub time: SystemTime,
+    pub text: String,
+    /// IRCv3 msgid, used to drop lines CHATHISTORY sends us twice.
+    #[serde(default)]
+    pub msgid: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ScrollbackConfig {
+    /// Lines kept in memory per buffer (HexChat's text_max_lines).
+    pub max_lines: usize,
+    /// Where evicted lines go; `None` keeps scrollback in memory only.
+    pub spill_dir: Option<PathBuf>,
+}
+
+impl Default for ScrollbackConfig {
+    fn default() -> Self { Self { max_lines: 5000, spill_dir: None } }
+}
+
+#[derive(Debug, Default)]
+struct Ring {
+    lines: VecDeque<Line>,
+    /// Per-buffer size from chanopt text_scrollback; overrides `max_lines`.
+    limit: Option<usize>,
+}
+
+#[derive(Debug)]
+pub struct Scrollback {
+    network: String,
+    cfg: ScrollbackConfig,
+    buffers: HashMap<BufferId, Ring>,
+}
+
+impl Scrollback {
+    pub fn new(network: impl Into<String>, cfg: ScrollbackConfig) -> Self {
+        Self { network: network.into(), cfg, buffers: HashMap::new() }
+    }
+
+    pub fn config(&self) -> &ScrollbackConfig { &self.cfg }
+    pub fn set_config(&mut self, cfg: ScrollbackConfig) { self.cfg = cfg; }
+
+    /// Per-buffer size limit; `None` falls back to the global `max_lines`.
+    pub fn set_limit(&mut self, id: BufferId, limit: Option<usize>) {
+        self.buffers.entry(id.clone()).or_default().limit = limit;
+        self.trim(&id);
+    }
+
+    pub fn buffers(&self) -> impl Iterator<Item = &BufferId> { self.buffers.keys() }
+    pub fn len(&self, id: &BufferId) -> usize { self.buffers.get(id).map_or(0, |r| r.lines.len()) }
+
+    pub fn push(&mut self, id: BufferId, time: SystemTime, text: impl Into<String>) {
+        self.buffers.entry(id.clone()).or_default().lines.push_back(Line { time, text: text.into(), msgid: None });
+        self.trim(&id);
+    }
+
+    /// Adds a line in time order, for backfilled history. Returns false, leaving
+    /// the buffer alone, when the line is already there by msgid or by time and text.
+    pub fn insert(&mut self, id: BufferId, line: Line) -> bool {
+        let ring = self.buffers.entry(id.clone()).or_default();
+        let dup = ring.lines.iter().rev().any(|l| match (&l.msgid, &line.msgid) {
+            (Some(a), Some(b)) => a == b,
+            _ => l.time == line.time && l.text == line.text,
+        });
+        if dup { return false; }
+        let at = ring.lines.iter().rposition(|l| l.time <= line.time).map_or(0, |i| i + 1);
+        ring.lines.insert(at, line);
+        self.trim(&id);
+        true
+    }
+
+    pub fn oldest(&self, id: &BufferId) -> Option<&Line> { self.buffers.get(id)?.lines.front() }
+    pub fn newest(&self, id: &BufferId) -> Option<&Line> { self.buffers.get(id)?.lines.back() }
+
+    fn trim(&mut self, id: &BufferId) {
+        let Some(ring) = self.buffers.get_mut(id) else { return };
+        let limit = ring.limit.unwrap_or(self.cfg.max_lines);
+        let mut evicted = Vec::new();
+        while ring.lines.len() > limit {
+            if let Some(l) = ring.lines.pop_front() { evicted.push(l); }
+        }
+        if !evicted.is_empty() {
+            if let Err(e) = self.spill(id, &evicted) {
+                tracing::warn!("scrollback spill for {:?} failed: {e}", id);
+            }
+        }
+    }
+
+    fn spill_path(&self, id: &BufferId) -> Option<PathBuf> {
+        let dir = self.cfg.spill_dir.as_ref()?;
+        let name = id.name()?;
+        Some(dir.join(file_name(&self.network)).join(format!("{}.txt", file_name(name))))
+    }
+
+    fn spill(&self, id: &BufferId, lines: &[Line]) -> Result<()> {
+        let Some(path) = self.spill_path(id) else { return Ok(()) };
+        if let Some(parent) = path.parent() { fs::create_dir_all(parent)?; }
+        let mut f = OpenOptions::new().create(true).append(true).open(&path)?;
+        for l in lines {
+            let stamp = l.time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+            writeln!(f, "T {} {}", stamp, l.text)?;
+        }
+        Ok(())
+    }
+
+    fn load_spilled(&self, id: &BufferId) -> Vec<Line> {
+        let Some(text) = self.spill_path(id).and_then(|p| fs::read_to_string(p).ok()) else { return Vec::new() };
+        text.lines().map(|raw| {
+            // lines without a valid "T <stamp> " header are kept with no timestamp, as HexChat does
+            match raw.strip_prefix("T ").and_then(|r| r.split_once(' ')) {
+                Some((stamp, rest)) if stamp.parse::<u64>().is_ok() => Line {
+                    time: UNIX_EPOCH + Duration::from_secs(stamp.parse().unwrap_or_default()),
+                    text: rest.to_string(),
+                    msgid: None,
+                },
+                _ => Line { time: UNIX_EPOCH, text: raw.to_string(), msgid: None },
+            }
+        }).collect()
+    }
+
+    /// Newest lines last. `offset` counts back from the newest line, so
+    /// `page(id, 0, 50)` is the last screenful and `page(id, 50, 50)` the one before.
+    /// Paging past the in-memory ring continues into the spill file.
+    pub fn page(&self, id: &BufferId, offset: usize, count: usize) -> Vec<Line> {
+        let mem: Vec<&Line> = self.buffers.get(id).map(|r| r.lines.iter().collect()).unwrap_or_default();
+        let mut all: Vec<Line> = Vec::new();
+        if offset + count > mem.len() { all = self.load_spilled(id); }
+        all.extend(mem.into_iter().cloned());
+        let end = all.len().saturating_sub(offset);
+        let start = end.saturating_sub(count);
+        all.drain(start..end).collect()
+    }
+
+    /// Case-insensitive substring search over the in-memory ring, oldest first.
+    pub fn search(&self, id: &BufferId, needle: &str) -> Vec<Line> {
+        let needle = needle.to_lowercase();
+        self.buffers.get(id)
+            .map(|r| r.lines.iter().filter(|l| l.text.to_lowercase().contains(&needle)).cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Moves a buffer's history to a new id, e.g. when a query partner changes nick.
+    pub fn rename(&mut self, from: &BufferId, to: BufferId) {
+        if let Some(ring) = self.buffers.remove(from) { self.buffers.insert(to, ring); }
+    }
+
+    /// Drops a buffer's history, including anything spilled to disk.
+    pub fn clear(&mut self, id: &BufferId) {
+        if let Some(r) = self.buffers.get_mut(id) { r.lines.clear(); }
+        if let Some(p) = self.spill_path(id) { let _ = fs::remove_file(p); }
+    }
+}
+
+// like HexChat's log_create_filename
+fn file_name(name: &str) -> String {
+    name.chars().map(|c| match c {
+        '/' | '\\' | '|' | '>' | '<' | ':' | '"' | '*' | '?' => '_',
+        c => c.to_ascii_lowercase(),
+    }).collect()
+}
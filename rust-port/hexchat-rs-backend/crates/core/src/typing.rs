@@ -0,0 +1,112 @@
+// +typing notifications (IRCv3 client tag over TAGMSG): who is typing where,
+// with the spec's timeouts, and rate limiting for our own notifications.
+use crate::scrollback::BufferId;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// An `active` notification counts for this long without a refresh.
+const ACTIVE_FOR: Duration = Duration::from_secs(6);
+const PAUSED_FOR: Duration = Duration::from_secs(30);
+/// We repeat `active` no more often than this while the user keeps typing.
+const RESEND_ACTIVE: Duration = Duration::from_secs(3);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TypingState {
+    Active,
+    Paused,
+    Done,
+}
+
+impl TypingState {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "active" => Some(TypingState::Active),
+            "paused" => Some(TypingState::Paused),
+            "done" => Some(TypingState::Done),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TypingState::Active => "active",
+            TypingState::Paused => "paused",
+            TypingState::Done => "done",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Typist {
+    state: TypingState,
+    at: Instant,
+    target: String,
+}
+
+#[derive(Debug, Default)]
+pub struct TypingTracker {
+    buffers: HashMap<BufferId, HashMap<String, Typist>>,
+    /// last notification we sent per target
+    sent: HashMap<String, (TypingState, Instant)>,
+}
+
+impl TypingTracker {
+    /// Nicks currently typing in `id`.
+    pub fn typing(&self, id: &BufferId) -> Vec<(String, TypingState)> {
+        let mut out: Vec<_> = self.buffers.get(id).into_iter().flatten().map(|(n, t)| (n.clone(), t.state)).collect();
+        out.sort_by(|a, b| a.0.cmp(&b.0));
+        out
+    }
+
+    pub(crate) fn update(&mut self, id: BufferId, nick: &str, target: &str, state: TypingState, now: Instant) {
+        let typists = self.buffers.entry(id.clone()).or_default();
+        if state == TypingState::Done {
+            typists.remove(nick);
+        } else {
+            typists.insert(nick.to_string(), Typist { state, at: now, target: target.to_string() });
+        }
+        if typists.is_empty() { self.buffers.remove(&id); }
+    }
+
+    /// Forgets `nick` in `id`, e.g. once their message arrives; true when they were typing.
+    pub(crate) fn clear(&mut self, id: &BufferId, nick: &str) -> bool {
+        let Some(typists) = self.buffers.get_mut(id) else { return false };
+        let was = typists.remove(nick).is_some();
+        if typists.is_empty() { self.buffers.remove(id); }
+        was
+    }
+
+    /// Drops notifications that went stale; returns (nick, target) of each.
+    pub(crate) fn expire(&mut self, now: Instant) -> Vec<(String, String)> {
+        let mut out = Vec::new();
+        for typists in self.buffers.values_mut() {
+            typists.retain(|nick, t| {
+                let ttl = if t.state == TypingState::Active { ACTIVE_FOR } else { PAUSED_FOR };
+                let keep = now.duration_since(t.at) < ttl;
+                if !keep { out.push((nick.clone(), t.target.clone())); }
+                keep
+            });
+        }
+        self.buffers.retain(|_, t| !t.is_empty());
+        out
+    }
+
+    /// Whether to send `state` to `target` now, recording it if so.
+    pub(crate) fn should_send(&mut self, target: &str, state: TypingState, now: Instant) -> bool {
+        let key = target.to_ascii_lowercase();
+        let send = match self.sent.get(&key) {
+            Some((last, at)) if *last == state => state == TypingState::Active && now.duration_since(*at) >= RESEND_ACTIVE,
+            Some(_) => true,
+            // nothing to end if we never started
+            None => state != TypingState::Done,
+        };
+        if send {
+            if state == TypingState::Done { self.sent.remove(&key); } else { self.sent.insert(key, (state, now)); }
+        }
+        send
+    }
+
+    /// Our message went out, which ends typing there without a `done`.
+    pub(crate) fn sent_message(&mut self, target: &str) { self.sent.remove(&target.to_ascii_lowercase()); }
+}
@@ -0,0 +1,182 @@
+// URL grabber, as in HexChat's url.c: links, irc:// URLs, channel names and
+// email addresses seen in messages, kept in a bounded list and optionally
+// appended to url.save.
+use crate::scrollback::BufferId;
+use crate::strip_formatting;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum UrlKind {
+    Url,
+    /// irc:// and ircs:// links
+    Irc,
+    Channel,
+    Email,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GrabbedUrl {
+    pub url: String,
+    pub kind: UrlKind,
+    pub buffer: BufferId,
+    pub nick: String,
+    pub time: SystemTime,
+}
+
+#[derive(Debug, Clone)]
+pub struct UrlGrabberConfig {
+    /// Entries kept; the oldest go first (url_grabber_limit).
+    pub max_entries: usize,
+    /// Every new URL is appended here, one per line (HexChat's url.save).
+    pub save_path: Option<PathBuf>,
+}
+
+impl Default for UrlGrabberConfig {
+    fn default() -> Self { Self { max_entries: 100, save_path: None } }
+}
+
+#[derive(Debug, Default)]
+pub struct UrlGrabber {
+    cfg: UrlGrabberConfig,
+    /// Oldest first, in time order.
+    entries: VecDeque<GrabbedUrl>,
+}
+
+impl UrlGrabber {
+    pub fn new(cfg: UrlGrabberConfig) -> Self { Self { cfg, entries: VecDeque::new() } }
+
+    pub fn config(&self) -> &UrlGrabberConfig { &self.cfg }
+    pub fn set_config(&mut self, cfg: UrlGrabberConfig) {
+        self.cfg = cfg;
+        self.trim();
+    }
+
+    /// Records what `text` links to. A URL seen again moves up to its latest mention.
+    pub(crate) fn grab(&mut self, buffer: &BufferId, nick: &str, time: SystemTime, text: &str, chantypes: &str) {
+        for (kind, url) in find_urls(text, chantypes) {
+            let known = match self.entries.iter().position(|e| e.url == url) {
+                Some(i) if self.entries[i].time >= time => continue,
+                Some(i) => { self.entries.remove(i); true }
+                None => false,
+            };
+            if !known {
+                if let Err(e) = self.save(&url) { tracing::warn!("url.save write failed: {e}"); }
+            }
+            let at = self.entries.iter().rposition(|e| e.time <= time).map_or(0, |i| i + 1);
+            self.entries.insert(at, GrabbedUrl { url, kind, buffer: buffer.clone(), nick: nick.to_string(), time });
+        }
+        self.trim();
+    }
+
+    /// Newest first.
+    pub fn list(&self) -> impl Iterator<Item = &GrabbedUrl> { self.entries.iter().rev() }
+
+    /// Entries whose URL, nick or buffer contains `query` (ignoring case), newest first.
+    pub fn search(&self, query: &str) -> Vec<&GrabbedUrl> {
+        let q = query.to_lowercase();
+        self.list().filter(|e| {
+            e.url.to_lowercase().contains(&q) || e.nick.to_lowercase().contains(&q)
+                || e.buffer.name().is_some_and(|b| b.to_lowercase().contains(&q))
+        }).collect()
+    }
+
+    /// Writes `<unix time>\t<buffer>\t<nick>\t<url>` per entry, oldest first.
+    pub fn export(&self, out: &mut impl Write) -> Result<()> {
+        for e in &self.entries {
+            let stamp = e.time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+            writeln!(out, "{}\t{}\t{}\t{}", stamp, e.buffer.name().unwrap_or(""), e.nick, e.url)?;
+        }
+        Ok(())
+    }
+
+    pub fn clear(&mut self) { self.entries.clear(); }
+
+    fn trim(&mut self) {
+        while self.entries.len() > self.cfg.max_entries { self.entries.pop_front(); }
+    }
+
+    fn save(&self, url: &str) -> Result<()> {
+        let Some(path) = &self.cfg.save_path else { return Ok(()) };
+        if let Some(parent) = path.parent() { fs::create_dir_all(parent)?; }
+        writeln!(OpenOptions::new().create(true).append(true).open(path)?, "{}", url)?;
+        Ok(())
+    }
+}
+
+/// Links in `text`, in order; `chantypes` tells channel names apart (CHANTYPES).
+pub fn find_urls(text: &str, chantypes: &str) -> Vec<(UrlKind, String)> {
+    strip_formatting(text).split_whitespace().filter_map(|w| {
+        let w = trim_word(w);
+        let kind = classify(w, chantypes)?;
+        Some((kind, w.to_string()))
+    }).collect()
+}
+
+fn classify(w: &str, chantypes: &str) -> Option<UrlKind> {
+    if let Some((scheme, rest)) = w.split_once("://") {
+        let valid = !rest.is_empty() && scheme.chars().next().is_some_and(|c| c.is_ascii_alphabetic())
+            && scheme.chars().all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c));
+        if !valid { return None; }
+        let irc = scheme.eq_ignore_ascii_case("irc") || scheme.eq_ignore_ascii_case("ircs");
+        return Some(if irc { UrlKind::Irc } else { UrlKind::Url });
+    }
+    if w.len() > 4 && w.get(..4).is_some_and(|p| p.eq_ignore_ascii_case("www.")) { return Some(UrlKind::Url); }
+    if w.chars().next().is_some_and(|c| chantypes.contains(c)) {
+        // a lone "#" or "#1" is not worth keeping
+        return (w.chars().skip(1).any(char::is_alphabetic) && !w.contains(',')).then_some(UrlKind::Channel);
+    }
+    let (local, domain) = w.split_once('@')?;
+    let email = !local.is_empty() && !domain.contains('@') && !w.contains('/')
+        && domain.split('.').count() > 1 && domain.split('.').all(|p| !p.is_empty());
+    email.then_some(UrlKind::Email)
+}
+
+/// Drops quotes, brackets and sentence punctuation around a word; a closing
+/// bracket stays when the word opens one too, as in wiki links.
+fn trim_word(w: &str) -> &str {
+    let mut w = w.trim_start_matches(['<', '(', '[', '{', '"', '\'']);
+    loop {
+        let Some(c) = w.chars().last() else { return w };
+        let open = match c {
+            ')' => '(',
+            ']' => '[',
+            '}' => '{',
+            '.' | ',' | ';' | ':' | '!' | '?' | '"' | '\'' | '>' => '\0',
+            _ => return w,
+        };
+        if open != '\0' && w.matches(open).count() >= w.matches(c).count() { return w; }
+        w = &w[..w.len() - 1];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn non_ascii_words() {
+        assert!(find_urls("hello 日本語 world", "#&").is_empty());
+        assert!(find_urls("€€ hi", "#&").is_empty());
+        let found = find_urls("見て www.例え.jp と #日本 et https://例え.jp/パス。", "#&");
+        assert_eq!(found, vec![
+            (UrlKind::Url, "www.例え.jp".to_string()),
+            (UrlKind::Channel, "#日本".to_string()),
+            (UrlKind::Url, "https://例え.jp/パス。".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn trims_punctuation() {
+        let found = find_urls("(https://en.wikipedia.org/wiki/Rust_(language)), mail me@example.org.", "#&");
+        assert_eq!(found, vec![
+            (UrlKind::Url, "https://en.wikipedia.org/wiki/Rust_(language)".to_string()),
+            (UrlKind::Email, "me@example.org".to_string()),
+        ]);
+    }
+}
@@ -0,0 +1,216 @@
+// WHOIS/WHOWAS/WHO reply collection: every numeric between the first reply
+// and the end-of marker is folded into one typed result.
+use proto::Message;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct WhoisResult {
+    pub nick: String,
+    pub user: Option<String>,
+    pub host: Option<String>,
+    pub realname: Option<String>,
+    pub server: Option<String>,
+    pub server_info: Option<String>,
+    pub channels: Vec<String>,
+    pub account: Option<String>,
+    pub away: Option<String>,
+    pub idle_secs: Option<u64>,
+    pub signon: Option<u64>,
+    pub operator: bool,
+    pub secure: bool,
+    /// 401/406: no such nick (or no history for WHOWAS)
+    pub not_found: bool,
+    /// Text of any other numerics received for this nick, in order.
+    pub other: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct WhoReply {
+    pub channel: Option<String>,
+    pub nick: String,
+    pub user: String,
+    pub host: String,
+    pub server: Option<String>,
+    /// H/G, then * for opers and channel status prefixes
+    pub flags: String,
+    pub account: Option<String>,
+    pub realname: String,
+}
+
+impl WhoReply {
+    pub fn away(&self) -> bool { self.flags.starts_with('G') }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct WhoResult {
+    pub mask: String,
+    pub replies: Vec<WhoReply>,
+}
+
+pub enum Completed {
+    Whois(WhoisResult),
+    WhoWas(WhoisResult),
+    Who(WhoResult),
+}
+
+/// Field selection for our WHOX requests; 354 replies carry them in this order.
+const WHOX_FIELDS: &str = "tcuhnfar";
+
+#[derive(Debug, Default)]
+pub struct InfoQueries {
+    whois: HashMap<String, WhoisResult>,
+    whowas: HashMap<String, WhoisResult>,
+    /// plain WHOs we sent, answered in order
+    who: VecDeque<WhoResult>,
+    /// WHOX requests we sent, by query token
+    whox: HashMap<String, WhoResult>,
+    whox_token: u16,
+}
+
+impl InfoQueries {
+    pub fn who_command(&mut self, mask: &str, whox: bool) -> String {
+        let pending = WhoResult { mask: mask.to_string(), replies: Vec::new() };
+        if whox {
+            self.whox_token = self.whox_token % 999 + 1;
+            self.whox.insert(self.whox_token.to_string(), pending);
+            format!("WHO {} %{},{}", mask, WHOX_FIELDS, self.whox_token)
+        } else {
+            self.who.push_back(pending);
+            format!("WHO {}", mask)
+        }
+    }
+
+    fn entry<'a>(map: &'a mut HashMap<String, WhoisResult>, nick: &str) -> &'a mut WhoisResult {
+        map.entry(nick.to_ascii_lowercase()).or_insert_with(|| WhoisResult { nick: nick.to_string(), ..Default::default() })
+    }
+
+    // 312 and 330 show up in both WHOIS and WHOWAS replies
+    fn target<'a>(&'a mut self, nick: &str) -> &'a mut WhoisResult {
+        let key = nick.to_ascii_lowercase();
+        if !self.whois.contains_key(&key) && self.whowas.contains_key(&key) {
+            return Self::entry(&mut self.whowas, nick);
+        }
+        Self::entry(&mut self.whois, nick)
+    }
+
+    fn who_current(&mut self) -> &mut WhoResult {
+        // a WHO we did not send ourselves (typed by the user) still gets collected
+        if self.who.is_empty() { self.who.push_back(WhoResult::default()); }
+        self.who.front_mut().unwrap()
+    }
+
+    /// Feeds a numeric; returns a result once its end-of marker arrives.
+    pub fn handle(&mut self, msg: &Message) -> Option<Completed> {
+        let p = |i: usize| msg.params.get(i).cloned().unwrap_or_default();
+        let nick = p(1);
+        match msg.command.as_str() {
+            "311" | "314" => {
+                let map = if msg.command == "311" { &mut self.whois } else { &mut self.whowas };
+                let r = Self::entry(map, &nick);
+                r.user = Some(p(2));
+                r.host = Some(p(3));
+                r.realname = Some(p(5));
+            }
+            "312" => { let r = self.target(&nick); r.server = Some(p(2)); r.server_info = Some(p(3)); }
+            "313" => self.target(&nick).operator = true,
+            "317" => {
+                let r = self.target(&nick);
+                r.idle_secs = p(2).parse().ok();
+                r.signon = p(3).parse().ok();
+            }
+            "319" => self.target(&nick).channels.extend(p(2).split_whitespace().map(str::to_string)),
+            "330" => self.target(&nick).account = Some(p(2)),
+            "301" => {
+                // also sent when messaging an away user; only collect it inside a WHOIS
+                if let Some(r) = self.whois.get_mut(&nick.to_ascii_lowercase()) { r.away = Some(p(2)); }
+            }
+            "671" => self.target(&nick).secure = true,
+            "401" => { if let Some(r) = self.whois.get_mut(&nick.to_ascii_lowercase()) { r.not_found = true; } }
+            "406" => Self::entry(&mut self.whowas, &nick).not_found = true,
+            "318" => {
+                let r = self.whois.remove(&nick.to_ascii_lowercase())
+                    .unwrap_or_else(|| WhoisResult { nick: nick.clone(), not_found: true, ..Default::default() });
+                return Some(Completed::Whois(r));
+            }
+            "369" => {
+                let r = self.whowas.remove(&nick.to_ascii_lowercase())
+                    .unwrap_or_else(|| WhoisResult { nick: nick.clone(), not_found: true, ..Default::default() });
+                return Some(Completed::WhoWas(r));
+            }
+            "352" => {
+                // <me> <channel> <user> <host> <server> <nick> <flags> :<hops> <realname>
+                let trailing = p(7);
+                let realname = trailing.split_once(' ').map(|(_, r)| r.to_string()).unwrap_or_default();
+                let channel = Some(p(1)).filter(|c| c != "*");
+                self.who_current().replies.push(WhoReply {
+                    channel, user: p(2), host: p(3), server: Some(p(4)), nick: p(5), flags: p(6), account: None, realname,
+                });
+            }
+            "354" => {
+                // <me> <token> <channel> <user> <host> <nick> <flags> <account> :<realname>
+                // only our own tokens promise that field layout
+                let pending = self.whox.get_mut(&p(1))?;
+                let channel = Some(p(2)).filter(|c| c != "*");
+                let account = Some(p(7)).filter(|a| a != "0" && !a.is_empty());
+                pending.replies.push(WhoReply {
+                    channel, user: p(3), host: p(4), server: None, nick: p(5), flags: p(6), account, realname: p(8),
+                });
+            }
+            "315" => {
+                let token = self.whox.iter().find(|(_, r)| r.mask.eq_ignore_ascii_case(&nick)).map(|(t, _)| t.clone());
+                let mut r = match token {
+                    Some(t) => self.whox.remove(&t).unwrap_or_default(),
+                    None => self.who.pop_front().unwrap_or_default(),
+                };
+                if r.mask.is_empty() { r.mask = nick; }
+                return Some(Completed::Who(r));
+            }
+            _ => {
+                // anything else addressed to a nick we are WHOISing belongs to it (338, 378, 276...)
+                let key = nick.to_ascii_lowercase();
+                if msg.command.len() == 3 && msg.command.chars().all(|c| c.is_ascii_digit()) {
+                    if let Some(r) = self.whois.get_mut(&key) {
+                        r.other.push(msg.params.iter().skip(2).cloned().collect::<Vec<_>>().join(" "));
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feed(q: &mut InfoQueries, line: &str) -> Option<Completed> { q.handle(&Message::parse(line).unwrap()) }
+
+    #[test]
+    fn whox_only_collects_our_token() {
+        let mut q = InfoQueries::default();
+        assert_eq!(q.who_command("#c", true), "WHO #c %tcuhnfar,1");
+        assert!(feed(&mut q, ":s 354 me 7 bob realhost.example :some other field").is_none());
+        assert!(feed(&mut q, ":s 354 me 1 #c alice host.example alice H acct :Alice").is_none());
+        let Some(Completed::Who(r)) = feed(&mut q, ":s 315 me #c :End of WHO") else { panic!("no WHO result") };
+        assert_eq!(r.mask, "#c");
+        assert_eq!(r.replies.len(), 1);
+        assert_eq!(r.replies[0].nick, "alice");
+        assert_eq!(r.replies[0].account.as_deref(), Some("acct"));
+    }
+
+    #[test]
+    fn who_updates_members_from_names() {
+        let engine = crate::Engine::new("net", "me");
+        engine.connected("me");
+        for line in [":s 005 me WHOX PREFIX=(ov)@+ :are supported", ":me!u@h JOIN #c", ":s 353 me = #c :@me +alice!a@host.example"] {
+            engine.on_message(Message::parse(line).unwrap());
+        }
+        assert_eq!(engine.state().users["alice"].host.as_deref(), Some("host.example"));
+        engine.who("#c");
+        for line in [":s 354 me 1 #c alice host.example alice H acct :Alice", ":s 315 me #c :End of WHO"] {
+            engine.on_message(Message::parse(line).unwrap());
+        }
+        assert_eq!(engine.state().users["alice"].account.as_deref(), Some("acct"));
+    }
+}
@@ -25,6 +25,9 @@ use tokio_rustls::{TlsConnector, client::TlsStream};
 
 use tracing::{debug, error};
 
+pub mod sendq;
+use sendq::{SendQueue, Throttle};
+
 pub enum TlsConfig {
     Off,
     Rustls { client_auth: Option<ClientAuth> },
@@ -44,6 +47,7 @@ pub struct Connection {
     stream: Io,
     buf: BytesMut,
     cb_tls_server_end_point: Option<Vec<u8>>,
+    sendq: SendQueue,
 }
 
 impl Connection {
@@ -56,6 +60,7 @@ impl Connection {
                 stream: Io::Tcp(tcp),
                 buf: BytesMut::with_capacity(4096),
                 cb_tls_server_end_point: None,
+                sendq: SendQueue::new(Throttle::default()),
             }),
             TlsConfig::Rustls { client_auth } => {
                 let mut roots = RootCertStore::empty();
@@ -123,6 +128,7 @@ impl Connection {
                     stream: Io::Tls(tls_stream),
                     buf: BytesMut::with_capacity(4096),
                     cb_tls_server_end_point: cb_tlsep,
+                    sendq: SendQueue::new(Throttle::default()),
                 })
             }
         }
@@ -142,6 +148,29 @@ impl Connection {
         Ok(())
     }
 
+    /// Queues a line behind the flood limiter and sends whatever it allows right away.
+    pub async fn send(&mut self, line: &str) -> Result<u64> {
+        let id = self.sendq.push(line);
+        self.flush_queue().await?;
+        Ok(id)
+    }
+
+    /// Writes every queued line the limiter currently allows.
+    pub async fn flush_queue(&mut self) -> Result<()> {
+        while let Some(line) = self.sendq.pop_ready(std::time::Instant::now()) {
+            self.send_raw(&line).await?;
+        }
+        Ok(())
+    }
+
+    /// Time until `flush_queue` can make progress; `None` when nothing is queued.
+    pub fn next_send_in(&self) -> Option<std::time::Duration> {
+        self.sendq.next_ready_in(std::time::Instant::now())
+    }
+
+    pub fn send_queue(&self) -> &SendQueue { &self.sendq }
+    pub fn send_queue_mut(&mut self) -> &mut SendQueue { &mut self.sendq }
+
     pub async fn next_message(&mut self) -> Result<proto::Message> {
         loop {
             if let Some(pos) = self.buf.iter().position(|&b| b == b'\n') {
@@ -174,20 +203,74 @@ pub mod cap_sasl {
     use rand::{RngCore, rngs::OsRng};
     use sha2::{Sha256, Sha512, Digest};
     use subtle::ConstantTimeEq;
-    use std::collections::HashSet;
+    use std::collections::{HashMap, HashSet};
     use tracing::{debug, error};
     use proto::Message;
 
     #[derive(Debug, Clone, Default)]
-    pub struct CapRequest { pub want: Vec<&'static str> }
+    pub struct CapRequest {
+        pub want: Vec<&'static str>,
+        /// soju.im/bouncer-networks netid to BOUNCER BIND this connection to.
+        pub bind_network: Option<String>,
+    }
     impl CapRequest {
         pub fn defaults(include_sasl: bool) -> Self {
-            let mut v = vec!["server-time", "message-tags"];
+            let mut v = vec!["server-time", "message-tags", "away-notify", "account-notify", "extended-join", "account-tag",
+                "chghost", "setname", "invite-notify", "batch", "multi-prefix", "userhost-in-names",
+                "draft/chathistory", "labeled-response", "echo-message", "draft/multiline",
+                "draft/read-marker", "soju.im/bouncer-networks", "soju.im/bouncer-networks-notify"];
             if include_sasl { v.push("sasl"); }
-            Self { want: v }
+            Self { want: v, bind_network: None }
+        }
+    }
+
+    // Fallback nicks tried when the server answers 432/433/436/437 during
+    // registration, mirroring HexChat's nick1/nick2/nick3 settings.
+    const MAX_NICK_SUFFIX: usize = 9;
+
+    #[derive(Debug, Clone)]
+    pub enum Regain {
+        Monitor,
+        Ghost { password: String },
+        Regain { password: String },
+    }
+
+    #[derive(Debug, Clone)]
+    pub struct NickPrefs {
+        pub primary: String,
+        pub alternates: Vec<String>,
+        pub regain: Option<Regain>,
+    }
+    impl NickPrefs {
+        pub fn new(primary: impl Into<String>) -> Self {
+            Self { primary: primary.into(), alternates: Vec::new(), regain: None }
+        }
+
+        /// Nick to try on the given attempt: primary, then alternates, then primary with a numeric suffix.
+        pub fn candidate(&self, attempt: usize) -> Option<String> {
+            if attempt == 0 { return Some(self.primary.clone()); }
+            if let Some(alt) = self.alternates.get(attempt - 1) { return Some(alt.clone()); }
+            let n = attempt - self.alternates.len();
+            if n > MAX_NICK_SUFFIX { None } else { Some(format!("{}{}", self.primary, n)) }
         }
     }
 
+    async fn regain_primary(conn: &mut Connection, nicks: &NickPrefs) -> Result<()> {
+        match &nicks.regain {
+            Some(Regain::Monitor) => conn.send_raw(&format!("MONITOR + {}", nicks.primary)).await?,
+            Some(Regain::Ghost{ password }) => {
+                conn.send_raw(&format!("PRIVMSG NickServ :GHOST {} {}", nicks.primary, password)).await?;
+                // services take a moment to kill the ghost; the nick is taken once MONITOR says it went offline
+                conn.send_raw(&format!("MONITOR + {}", nicks.primary)).await?;
+            }
+            Some(Regain::Regain{ password }) => {
+                conn.send_raw(&format!("PRIVMSG NickServ :REGAIN {} {}", nicks.primary, password)).await?;
+            }
+            None => {}
+        }
+        Ok(())
+    }
+
     #[derive(Debug, Clone)]
     pub enum SaslMech {
         Plain { authzid: Option<String>, username: String, password: String },
@@ -224,15 +307,28 @@ pub mod cap_sasl {
         Ok(ScramParsed{ salt, iter: iter.context("missing iterations")?, nonce: nonce.context("missing nonce")? })
     }
 
-    pub async fn negotiate(conn: &mut Connection, nick: &str, user: &str, realname: &str, caps: CapRequest, sasl: Option<SaslMech>) -> Result<()> {
-        conn.send_raw(&format!("NICK {}", nick)).await?;
+    /// What registration ended with.
+    #[derive(Debug, Clone)]
+    pub struct Registered {
+        /// The nick the server accepted.
+        pub nick: String,
+        /// Capabilities the server ACKed, as `name` or `name=value` from CAP LS.
+        pub caps: Vec<String>,
+    }
+
+    /// Registers with the server.
+    pub async fn negotiate(conn: &mut Connection, nicks: &NickPrefs, user: &str, realname: &str, caps: CapRequest, sasl: Option<SaslMech>) -> Result<Registered> {
+        let mut nick_attempt = 0;
+        conn.send_raw(&format!("NICK {}", nicks.primary)).await?;
         conn.send_raw(&format!("USER {} 0 * :{}", user, realname)).await?;
         conn.send_raw("CAP LS 302").await?;
 
         let mut cap_in_progress = true;
-        let mut ls_partial: HashSet<String> = HashSet::new();
+        // advertised caps and their values (sasl=PLAIN,EXTERNAL, draft/multiline=max-bytes=4096...)
+        let mut ls_partial: HashMap<String, String> = HashMap::new();
         let want: HashSet<String> = caps.want.iter().map(|s| s.to_string()).collect();
         let mut req_sent = false;
+        let mut enabled: Vec<String> = Vec::new();
 
         let mut scram_client_nonce: Option<String> = None;
         let mut scram_cfb: Option<String> = None;
@@ -242,22 +338,45 @@ pub mod cap_sasl {
             let msg = conn.next_message().await?;
             let cmd = msg.command.as_str();
 
+            // some servers (hybrid, ratbox, unreal, inspircd) hold registration until the cookie comes back
+            if cmd == "PING" {
+                conn.send_raw(&format!("PONG :{}", msg.params.last().map(String::as_str).unwrap_or(""))).await?;
+                continue;
+            }
             if cmd == "CAP" {
                 let sub = msg.params.get(1).map(String::as_str).unwrap_or("");
                 match sub {
                     "LS" => {
                         if let Some(caps_str) = msg.params.last() {
-                            for c in caps_str.split_whitespace() { ls_partial.insert(c.to_string()); }
+                            for c in caps_str.split_whitespace() {
+                                let (name, value) = c.split_once('=').unwrap_or((c, ""));
+                                ls_partial.insert(name.to_string(), value.to_string());
+                            }
                         }
                         let is_cont = msg.params.iter().any(|p| p == "*");
                         if !is_cont && !req_sent {
-                            let to_req: Vec<String> = want.intersection(&ls_partial).cloned().collect();
+                            let to_req: Vec<String> = want.iter().filter(|w| ls_partial.contains_key(*w)).cloned().collect();
                             if !to_req.is_empty() { conn.send_raw(&format!("CAP REQ :{}", to_req.join(" "))).await?; req_sent = True; }
                             else { conn.send_raw("CAP END").await?; cap_in_progress = false; }
                         }
                     }
                     "ACK" => {
                         let ackd = msg.params.last().cloned().unwrap_or_default();
+                        for c in ackd.split_whitespace() {
+                            match c.strip_prefix('-') {
+                                Some(off) => enabled.retain(|e| e.split('=').next() != Some(off)),
+                                None => enabled.push(match ls_partial.get(c) {
+                                    Some(v) if !v.is_empty() => format!("{}={}", c, v),
+                                    _ => c.to_string(),
+                                }),
+                            }
+                        }
+                        // binding has to happen before CAP END
+                        if let Some(id) = &caps.bind_network {
+                            if ackd.split_whitespace().any(|c| c == "soju.im/bouncer-networks") {
+                                conn.send_raw(&format!("BOUNCER BIND {}", id)).await?;
+                            }
+                        }
                         if ackd.split_whitespace().any(|c| c == "sasl") && sasl.is_some() {
                             match &sasl {
                                 Some(SaslMech::Plain{..}) => conn.send_raw("AUTHENTICATE PLAIN").await?,
@@ -274,6 +393,10 @@ pub mod cap_sasl {
                                 Some(SaslMech::External{..}) => conn.send_raw("AUTHENTICATE EXTERNAL").await?,
                                 None => {}
                             }
+                        } else if cap_in_progress {
+                            // nothing left to negotiate
+                            conn.send_raw("CAP END").await?;
+                            cap_in_progress = false;
                         }
                     }
                     "NAK" => {
@@ -426,8 +549,20 @@ pub mod cap_sasl {
                 if cap_in_progress { conn.send_raw("CAP END").await?; cap_in_progress = false; }
                 bail!("SASL failed with {}", cmd);
             }
-            if cmd == "001" { break; }
+            // nick rejected before registration completed: move on to the next choice
+            if cmd == "432" || cmd == "433" || cmd == "436" || cmd == "437" {
+                let rejected = msg.params.get(1).cloned().unwrap_or_default();
+                nick_attempt += 1;
+                let next = nicks.candidate(nick_attempt).context("all nicknames are in use")?;
+                debug!("nick {} rejected with {}, trying {}", rejected, cmd, next);
+                conn.send_raw(&format!("NICK {}", next)).await?;
+                continue;
+            }
+            if cmd == "001" {
+                let nick = msg.params.first().cloned().unwrap_or_else(|| nicks.primary.clone());
+                if nick != nicks.primary { regain_primary(conn, nicks).await?; }
+                return Ok(Registered { nick, caps: enabled });
+            }
         }
-        Ok(())
     }
 }
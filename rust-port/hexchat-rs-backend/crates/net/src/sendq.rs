@@ -0,0 +1,169 @@
+// Outbound send queue with token-bucket flood protection, modeled on
+// HexChat's server.c tcp_send_len/tcp_send_queue and its net_throttle pref.
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Higher priorities leave the queue first; equal priorities keep FIFO order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// WHO and MODE queries
+    Low,
+    /// PRIVMSG and NOTICE, and the BATCH lines around multiline messages
+    Message,
+    Normal,
+    /// PONG and QUIT
+    Urgent,
+}
+
+impl Priority {
+    pub fn for_line(line: &str) -> Self {
+        let mut words = line.split(' ').filter(|w| !w.is_empty()).skip_while(|w| w.starts_with('@'));
+        let cmd = words.next().unwrap_or("").to_ascii_uppercase();
+        match cmd.as_str() {
+            "PONG" | "QUIT" => Priority::Urgent,
+            // a batch's open and close must not overtake the messages inside it
+            "PRIVMSG" | "NOTICE" | "BATCH" => Priority::Message,
+            "WHO" => Priority::Low,
+            // a MODE without +/- in the mode string is only a query
+            "MODE" => match words.nth(1) {
+                Some(modes) if modes.contains('+') || modes.contains('-') => Priority::Normal,
+                _ => Priority::Low,
+            },
+            _ => Priority::Normal,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Throttle {
+    /// Lines that may be sent back to back before the limiter kicks in.
+    pub burst: u32,
+    /// Time to earn back one line.
+    pub interval: Duration,
+}
+
+impl Default for Throttle {
+    fn default() -> Self { Self { burst: 5, interval: Duration::from_secs(2) } }
+}
+
+impl Throttle {
+    /// No limiting at all (net_throttle off).
+    pub fn off() -> Self { Self { burst: 0, interval: Duration::ZERO } }
+    pub fn is_off(&self) -> bool { self.interval.is_zero() }
+}
+
+#[derive(Debug, Clone)]
+pub struct Queued {
+    pub id: u64,
+    pub priority: Priority,
+    pub line: String,
+}
+
+#[derive(Debug)]
+pub struct SendQueue {
+    throttle: Throttle,
+    lines: VecDeque<Queued>,
+    tokens: f64,
+    refilled_at: Instant,
+    next_id: u64,
+}
+
+impl SendQueue {
+    pub fn new(throttle: Throttle) -> Self {
+        Self { throttle, lines: VecDeque::new(), tokens: throttle.burst as f64, refilled_at: Instant::now(), next_id: 1 }
+    }
+
+    pub fn throttle(&self) -> Throttle { self.throttle }
+    pub fn set_throttle(&mut self, throttle: Throttle) {
+        self.throttle = throttle;
+        self.tokens = self.tokens.min(throttle.burst as f64);
+    }
+
+    /// Queues a line with the priority derived from its command; returns an id for `cancel`.
+    pub fn push(&mut self, line: impl Into<String>) -> u64 {
+        let line = line.into();
+        let priority = Priority::for_line(&line);
+        self.push_with(line, priority)
+    }
+
+    pub fn push_with(&mut self, line: impl Into<String>, priority: Priority) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        let at = self.lines.iter().position(|q| q.priority < priority).unwrap_or(self.lines.len());
+        self.lines.insert(at, Queued { id, priority, line: line.into() });
+        id
+    }
+
+    pub fn len(&self) -> usize { self.lines.len() }
+    pub fn is_empty(&self) -> bool { self.lines.is_empty() }
+    /// Bytes waiting to be sent, like HexChat's sendq_len.
+    pub fn bytes(&self) -> usize { self.lines.iter().map(|q| q.line.len()).sum() }
+    pub fn iter(&self) -> impl Iterator<Item = &Queued> { self.lines.iter() }
+
+    pub fn cancel(&mut self, id: u64) -> bool {
+        let before = self.lines.len();
+        self.lines.retain(|q| q.id != id);
+        self.lines.len() != before
+    }
+
+    /// Drops every queued line matching `pred`; returns how many were removed.
+    pub fn cancel_where(&mut self, mut pred: impl FnMut(&Queued) -> bool) -> usize {
+        let before = self.lines.len();
+        self.lines.retain(|q| !pred(q));
+        before - self.lines.len()
+    }
+
+    pub fn clear(&mut self) -> usize {
+        let n = self.lines.len();
+        self.lines.clear();
+        n
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if self.throttle.is_off() { return; }
+        let elapsed = now.saturating_duration_since(self.refilled_at);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() / self.throttle.interval.as_secs_f64())
+            .min(self.throttle.burst.max(1) as f64);
+        self.refilled_at = now;
+    }
+
+    /// Takes the next line if the limiter allows sending it now.
+    pub fn pop_ready(&mut self, now: Instant) -> Option<String> {
+        if self.lines.is_empty() { return None; }
+        if self.throttle.is_off() { return self.lines.pop_front().map(|q| q.line); }
+        self.refill(now);
+        if self.tokens < 1.0 { return None; }
+        self.tokens -= 1.0;
+        self.lines.pop_front().map(|q| q.line)
+    }
+
+    /// How long until the next queued line may go out; `None` when the queue is empty.
+    pub fn next_ready_in(&self, now: Instant) -> Option<Duration> {
+        if self.lines.is_empty() { return None; }
+        if self.throttle.is_off() { return Some(Duration::ZERO); }
+        let elapsed = now.saturating_duration_since(self.refilled_at).as_secs_f64() / self.throttle.interval.as_secs_f64();
+        let missing = 1.0 - (self.tokens + elapsed);
+        if missing <= 0.0 { Some(Duration::ZERO) } else { Some(self.throttle.interval.mul_f64(missing)) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn batch_keeps_its_order_under_throttling() {
+        let mut q = SendQueue::new(Throttle::default());
+        let mut sent = Vec::new();
+        let mut batch = vec!["BATCH +ml2 draft/multiline #c".to_string()];
+        batch.extend((1..=8).map(|i| format!("@batch=ml2 PRIVMSG #c :part {}", i)));
+        batch.push("BATCH -ml2".to_string());
+        let now = Instant::now();
+        for line in &batch[..6] { q.push(line.as_str()); }
+        while let Some(l) = q.pop_ready(now) { sent.push(l); }
+        for line in &batch[6..] { q.push(line.as_str()); }
+        let later = now + Duration::from_secs(60);
+        while let Some(l) = q.pop_ready(later) { sent.push(l); }
+        assert_eq!(sent, batch);
+    }
+}
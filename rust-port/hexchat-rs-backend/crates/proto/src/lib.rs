@@ -1,12 +1,26 @@
 use anyhow::Result;
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 #[derive(Debug, Clone)]
 pub struct Prefix {
     pub raw: String,
 }
 
+impl Prefix {
+    /// The nick (or server name) part of `nick!user@host`.
+    pub fn nick(&self) -> &str { self.raw.split('!').next().unwrap_or(&self.raw) }
+    pub fn user(&self) -> Option<&str> {
+        let rest = self.raw.split_once('!')?.1;
+        Some(rest.split('@').next().unwrap_or(rest))
+    }
+    pub fn host(&self) -> Option<&str> { self.raw.split_once('@').map(|(_, h)| h) }
+}
+
 #[derive(Debug, Clone)]
 pub struct Message {
+    /// IRCv3 message tags, values unescaped; valueless tags map to "".
+    pub tags: BTreeMap<String, String>,
     pub prefix: Option<Prefix>,
     pub command: String,
     pub params: Vec<String>,
@@ -15,6 +29,12 @@ pub struct Message {
 impl Message {
     pub fn parse(line: &str) -> Result<Self> {
         let mut s = line.trim().to_string();
+        let mut tags = BTreeMap::new();
+        if s.starts_with('@') {
+            let (raw, rest) = s[1..].split_once(' ').unwrap_or((&s[1..], ""));
+            tags = parse_tags(raw);
+            s = rest.trim_start().to_string();
+        }
         let prefix = if s.starts_with(':') {
             if let Some(space) = s.find(' ') {
                 let p = Prefix { raw: s[1..space].to_string() };
@@ -40,11 +60,24 @@ impl Message {
                 params.push(tok.to_string());
             }
         }
-        Ok(Message { prefix, command, params })
+        Ok(Message { tags, prefix, command, params })
     }
 
+    pub fn tag(&self, key: &str) -> Option<&str> { self.tags.get(key).map(String::as_str) }
+
+    /// The server-time `time` tag, e.g. `2011-10-19T16:40:51.620Z`.
+    pub fn server_time(&self) -> Option<SystemTime> { self.tag("time").and_then(parse_server_time) }
+
     pub fn to_string(&self) -> String {
         let mut out = String::new();
+        if !self.tags.is_empty() {
+            out.push('@');
+            let tags: Vec<String> = self.tags.iter()
+                .map(|(k, v)| if v.is_empty() { k.clone() } else { format!("{}={}", k, escape_tag(v)) })
+                .collect();
+            out.push_str(&tags.join(";"));
+            out.push(' ');
+        }
         if let Some(p) = &self.prefix {
             out.push(':');
             out.push_str(&p.raw);
@@ -63,3 +96,98 @@ impl Message {
         out
     }
 }
+
+/// Parses `key=value;key2` in message tag syntax, also used for soju's network attributes.
+pub fn parse_tags(raw: &str) -> BTreeMap<String, String> {
+    raw.split(';').filter(|t| !t.is_empty()).map(|tag| {
+        let (k, v) = tag.split_once('=').unwrap_or((tag, ""));
+        (k.to_string(), unescape_tag(v))
+    }).collect()
+}
+
+fn unescape_tag(v: &str) -> String {
+    let mut out = String::with_capacity(v.len());
+    let mut it = v.chars();
+    while let Some(c) = it.next() {
+        if c != '\\' { out.push(c); continue; }
+        match it.next() {
+            Some(':') => out.push(';'),
+            Some('s') => out.push(' '),
+            Some('r') => out.push('\r'),
+            Some('n') => out.push('\n'),
+            Some(c) => out.push(c),
+            None => {}
+        }
+    }
+    out
+}
+
+/// Escapes a message tag value for sending.
+pub fn escape_tag(v: &str) -> String {
+    let mut out = String::with_capacity(v.len());
+    for c in v.chars() {
+        match c {
+            ';' => out.push_str("\\:"),
+            ' ' => out.push_str("\\s"),
+            '\\' => out.push_str("\\\\"),
+            '\r' => out.push_str("\\r"),
+            '\n' => out.push_str("\\n"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Parses an ISO 8601 UTC timestamp as sent in the server-time `time` tag.
+pub fn parse_server_time(s: &str) -> Option<SystemTime> {
+    let s = s.strip_suffix('Z')?;
+    let (date, time) = s.split_once('T')?;
+    let mut d = date.splitn(3, '-').map(|n| n.parse::<i64>().ok());
+    let (y, m, day) = (d.next()??, d.next()??, d.next()??);
+    let (hms, frac) = time.split_once('.').unwrap_or((time, ""));
+    let mut t = hms.splitn(3, ':').map(|n| n.parse::<u64>().ok());
+    let (hh, mm, ss) = (t.next()??, t.next()??, t.next()??);
+    if !(1..=12).contains(&m) || !(1..=31).contains(&day) || hh > 23 || mm > 59 || ss > 60 { return None; }
+    // days since the epoch, from Howard Hinnant's days_from_civil
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * ((m + 9) % 12) + 2) / 5 + day - 1;
+    let days = era * 146097 + yoe * 365 + yoe / 4 - yoe / 100 + doy - 719468;
+    let secs = u64::try_from(days).ok()? * 86400 + hh * 3600 + mm * 60 + ss;
+    if !frac.bytes().all(|b| b.is_ascii_digit()) { return None; }
+    let millis = format!("{:0<3}", &frac[..frac.len().min(3)]).parse::<u64>().ok()?;
+    Some(UNIX_EPOCH + Duration::from_secs(secs) + Duration::from_millis(millis))
+}
+
+/// Formats a time the way server-time and CHATHISTORY `timestamp=` expect.
+pub fn format_server_time(t: SystemTime) -> String {
+    let d = t.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = d.as_secs();
+    // civil_from_days, the inverse of the above
+    let z = (secs / 86400) as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + i64::from(m <= 2);
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z", y, m, day,
+        secs / 3600 % 24, secs / 60 % 60, secs % 60, d.subsec_millis())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn server_time_fractions() {
+        let t = parse_server_time("2024-03-01T12:00:00.5Z").unwrap();
+        assert_eq!(format_server_time(t), "2024-03-01T12:00:00.500Z");
+        assert!(parse_server_time("2024-03-01T12:00:00Z").is_some());
+        assert_eq!(parse_server_time("2024-03-01T12:00:00.abéZ"), None);
+        assert_eq!(parse_server_time("2024-03-01T12:00:00.1éZ"), None);
+    }
+}